@@ -0,0 +1,89 @@
+use accessfix::{barrier_nodes, filter_driveways};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, Tags, Way, WayId};
+use std::collections::{BTreeMap, HashSet};
+
+/// A grid of two-segment driveways, where every tenth way has a gate
+/// on its middle node
+fn synthetic(ways: i64) -> BTreeMap<OsmId, OsmObj> {
+    let mut data = BTreeMap::new();
+    for i in 0..ways {
+        let mut way_tags = Tags::new();
+        way_tags.insert("service".into(), "driveway".into());
+        way_tags.insert("access".into(), "private".into());
+        let nodes: Vec<_> = (0..3).map(|n| NodeId(i * 3 + n)).collect();
+        for (n, id) in nodes.iter().enumerate() {
+            let mut tags = Tags::new();
+            if n == 1 && i % 10 == 0 {
+                tags.insert("barrier".into(), "gate".into());
+            }
+            let obj = OsmObj::Node(Node {
+                id: *id,
+                tags,
+                decimicro_lat: 430_000_000 + id.0 as i32,
+                decimicro_lon: -710_000_000 - id.0 as i32,
+                info: Default::default(),
+            });
+            data.insert(obj.id(), obj);
+        }
+        let obj = OsmObj::Way(Way {
+            id: WayId(i),
+            tags: way_tags,
+            nodes,
+            info: Default::default(),
+        });
+        data.insert(obj.id(), obj);
+    }
+    data
+}
+
+fn filter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("filter_driveways");
+    for &ways in &[1_000, 100_000] {
+        let data = synthetic(ways);
+        group.throughput(Throughput::Elements(data.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(ways), &data, |b, data| {
+            b.iter(|| filter_driveways(data, &barrier_nodes(data)))
+        });
+    }
+    group.finish();
+}
+
+/// The per-way poison check on its own, against the old approach of
+/// collecting each way's nodes into a set and intersecting
+fn poison_check(c: &mut Criterion) {
+    let data = synthetic(100_000);
+    let poison_nodes = barrier_nodes(&data);
+    let ways: Vec<_> = data
+        .values()
+        .filter_map(|obj| match obj {
+            OsmObj::Way(w) => Some(w),
+            _ => None,
+        })
+        .collect();
+    let mut group = c.benchmark_group("poison_check");
+    group.throughput(Throughput::Elements(ways.len() as u64));
+    group.bench_function("set_per_way", |b| {
+        b.iter(|| {
+            ways.iter()
+                .filter(|w| {
+                    poison_nodes
+                        .intersection(&w.nodes.iter().copied().collect::<HashSet<_>>())
+                        .next()
+                        .is_none()
+                })
+                .count()
+        })
+    });
+    group.bench_function("lookup", |b| {
+        b.iter(|| {
+            ways.iter()
+                .filter(|w| !w.nodes.iter().any(|n| poison_nodes.contains(n)))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, filter, poison_check);
+criterion_main!(benches);
@@ -0,0 +1,213 @@
+//! Polygon boundaries for limiting the output to an area,
+//! read from either an Osmosis `.poly` file or GeoJSON
+use std::error::Error;
+use std::io::{prelude::*, BufReader};
+use std::path::Path;
+
+/// A ring of (lon, lat) points
+type Ring = Vec<(f64, f64)>;
+
+/// One outer ring with the holes cut out of it
+struct Polygon {
+    outer: Ring,
+    holes: Vec<Ring>,
+}
+
+/// An area made up of one or more polygons
+pub struct Boundary {
+    polygons: Vec<Polygon>,
+}
+
+impl Boundary {
+    /// Read a boundary, picking the format from the file extension
+    pub fn from_file(path: &Path) -> Result<Boundary, Box<dyn Error>> {
+        let file = std::fs::File::open(path)?;
+        let reader = BufReader::new(file);
+        let boundary = match path.extension().and_then(|x| x.to_str()) {
+            Some("poly") => parse_poly(reader)?,
+            _ => parse_geojson(reader)?,
+        };
+        if boundary.polygons.is_empty() {
+            return Err(format!("{} contains no polygons", path.display()).into());
+        }
+        Ok(boundary)
+    }
+
+    /// Whether the point is inside one of the polygons and not in one of its holes
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.polygons.iter().any(|p| {
+            ring_contains(&p.outer, lon, lat) && !p.holes.iter().any(|h| ring_contains(h, lon, lat))
+        })
+    }
+}
+
+/// Even-odd ray casting test
+fn ring_contains(ring: &[(f64, f64)], x: f64, y: f64) -> bool {
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len() {
+        let (xi, yi) = ring[i];
+        let (xj, yj) = ring[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// Parse the Osmosis polygon format. Sections whose name starts
+/// with `!` are holes and are cut out of every outer ring.
+fn parse_poly<R: BufRead>(reader: R) -> Result<Boundary, Box<dyn Error>> {
+    let mut lines = reader.lines();
+    // The first line is the name of the whole file
+    lines.next().transpose()?;
+    let mut outers = Vec::new();
+    let mut holes = Vec::new();
+    loop {
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Err("unexpected end of poly file".into()),
+        };
+        let header = header.trim();
+        if header == "END" {
+            break;
+        }
+        let mut ring = Vec::new();
+        loop {
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Err("unexpected end of poly file".into()),
+            };
+            let line = line.trim();
+            if line == "END" {
+                break;
+            }
+            let mut parts = line.split_whitespace().map(|x| x.parse::<f64>());
+            match (parts.next(), parts.next()) {
+                (Some(lon), Some(lat)) => ring.push((lon?, lat?)),
+                _ => return Err(format!("bad poly coordinate line: {}", line).into()),
+            }
+        }
+        if header.starts_with('!') {
+            holes.push(ring);
+        } else {
+            outers.push(ring);
+        }
+    }
+    let polygons = outers
+        .into_iter()
+        .map(|outer| Polygon {
+            outer,
+            holes: holes.clone(),
+        })
+        .collect();
+    Ok(Boundary { polygons })
+}
+
+/// Parse a GeoJSON Polygon or MultiPolygon, which may be wrapped
+/// in a Feature or FeatureCollection
+fn parse_geojson<R: Read>(reader: R) -> Result<Boundary, Box<dyn Error>> {
+    let value: serde_json::Value = serde_json::from_reader(reader)?;
+    let mut polygons = Vec::new();
+    collect_geojson(&value, &mut polygons)?;
+    Ok(Boundary { polygons })
+}
+
+fn collect_geojson(
+    value: &serde_json::Value,
+    polygons: &mut Vec<Polygon>,
+) -> Result<(), Box<dyn Error>> {
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in value["features"].as_array().into_iter().flatten() {
+                collect_geojson(feature, polygons)?;
+            }
+        }
+        Some("Feature") => collect_geojson(&value["geometry"], polygons)?,
+        Some("Polygon") => polygons.push(geojson_polygon(&value["coordinates"])?),
+        Some("MultiPolygon") => {
+            for coords in value["coordinates"].as_array().into_iter().flatten() {
+                polygons.push(geojson_polygon(coords)?);
+            }
+        }
+        other => return Err(format!("unsupported GeoJSON type {:?}", other).into()),
+    }
+    Ok(())
+}
+
+fn geojson_polygon(coords: &serde_json::Value) -> Result<Polygon, Box<dyn Error>> {
+    let mut rings = coords
+        .as_array()
+        .ok_or("polygon coordinates are not an array")?
+        .iter()
+        .map(geojson_ring)
+        .collect::<Result<Vec<_>, _>>()?;
+    if rings.is_empty() {
+        return Err("polygon has no rings".into());
+    }
+    let outer = rings.remove(0);
+    Ok(Polygon {
+        outer,
+        holes: rings,
+    })
+}
+
+fn geojson_ring(coords: &serde_json::Value) -> Result<Ring, Box<dyn Error>> {
+    coords
+        .as_array()
+        .ok_or("ring is not an array")?
+        .iter()
+        .map(|point| match (point[0].as_f64(), point[1].as_f64()) {
+            (Some(lon), Some(lat)) => Ok((lon, lat)),
+            _ => Err("bad GeoJSON position".into()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLY: &str = "test area
+1
+   -72.0 43.0
+   -71.0 43.0
+   -71.0 44.0
+   -72.0 44.0
+   -72.0 43.0
+END
+!2
+   -71.6 43.4
+   -71.4 43.4
+   -71.4 43.6
+   -71.6 43.6
+   -71.6 43.4
+END
+END
+";
+
+    #[test]
+    fn poly_with_hole() {
+        let boundary = parse_poly(POLY.as_bytes()).unwrap();
+        assert!(boundary.contains(43.2, -71.8));
+        assert!(!boundary.contains(43.5, -71.5));
+        assert!(!boundary.contains(45.0, -71.5));
+    }
+
+    #[test]
+    fn geojson_multipolygon_with_hole() {
+        let json = r#"{"type": "Feature", "properties": {}, "geometry": {
+            "type": "MultiPolygon",
+            "coordinates": [
+                [[[-72, 43], [-71, 43], [-71, 44], [-72, 44], [-72, 43]],
+                 [[-71.6, 43.4], [-71.4, 43.4], [-71.4, 43.6], [-71.6, 43.6], [-71.6, 43.4]]],
+                [[[-70, 43], [-69, 43], [-69, 44], [-70, 44], [-70, 43]]]
+            ]}}"#;
+        let boundary = parse_geojson(json.as_bytes()).unwrap();
+        assert!(boundary.contains(43.2, -71.8));
+        assert!(!boundary.contains(43.5, -71.5));
+        assert!(boundary.contains(43.5, -69.5));
+        assert!(!boundary.contains(43.5, -70.5));
+    }
+}
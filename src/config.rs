@@ -0,0 +1,124 @@
+//! Reading a run's options from a TOML or JSON file, so a review run can
+//! be committed and repeated. Each key is a long option name (`min-length`
+//! or `min_length`), and the file is turned back into command line arguments
+//! so it goes through exactly the same parsing as the flags do.
+use serde_json::Value;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// The command line with the `--config` file's arguments put in front, so
+/// flags given on the command line override them. The file is found by
+/// looking for `--config` before anything is parsed, so the options are
+/// only checked once, with both sets in place.
+pub fn with_file_args(args: Vec<OsString>) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let mut path = None;
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            path = rest.next().map(PathBuf::from);
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(PathBuf::from(value));
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let mut merged: Vec<_> = args.iter().take(1).cloned().collect();
+    merged.extend(args_from_file(&path)?);
+    merged.extend(args.into_iter().skip(1));
+    Ok(merged)
+}
+
+/// The arguments a config file stands for. JSON is picked by a `.json`
+/// extension, anything else is read as TOML.
+pub fn args_from_file(path: &Path) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let value = match path.extension().and_then(|x| x.to_str()) {
+        Some("json") => serde_json::from_str(&text)?,
+        _ => serde_json::to_value(toml::from_str::<toml::Value>(&text)?)?,
+    };
+    match value {
+        Value::Object(table) => to_args(&table),
+        _ => Err(format!("{} should hold a table of options", path.display()).into()),
+    }
+}
+
+/// Flags counted by how often they're given, so a number in
+/// the file means giving the flag that many times
+const COUNTED: &[&str] = &["--verbose"];
+
+fn to_args(table: &serde_json::Map<String, Value>) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let flag = format!("--{}", key.replace('_', "-"));
+        if flag == "--config" {
+            return Err("a config file can't load another one".into());
+        }
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => args.push(flag.clone().into()),
+                Value::Number(n) if COUNTED.contains(&flag.as_str()) => match n.as_u64() {
+                    Some(n) => args.extend((0..n).map(|_| flag.clone().into())),
+                    None => return Err(format!("{} needs a whole number", key).into()),
+                },
+                Value::Bool(false) => {}
+                Value::String(s) => args.extend(vec![flag.clone().into(), s.into()]),
+                Value::Number(n) => args.extend(vec![flag.clone().into(), n.to_string().into()]),
+                _ => return Err(format!("can't use {} for {}", value, key).into()),
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_becomes_arguments() {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        std::io::Write::write_all(
+            &mut file,
+            br#"
+                input = ["a.osm.pbf", "b.osm.pbf"]
+                min_length = 5.5
+                retag = true
+                stats = false
+                verbose = 2
+                veto-tags = "locked"
+            "#,
+        )
+        .unwrap();
+        let args: Vec<_> = args_from_file(file.path())
+            .unwrap()
+            .into_iter()
+            .map(|x| x.into_string().unwrap())
+            .collect();
+        assert_eq!(
+            args,
+            vec![
+                "--input",
+                "a.osm.pbf",
+                "--input",
+                "b.osm.pbf",
+                "--min-length",
+                "5.5",
+                "--retag",
+                "--verbose",
+                "--verbose",
+                "--veto-tags",
+                "locked",
+            ]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Loading the output straight into a running JOSM through its
+//! remote control, see <https://josm.openstreetmap.de/wiki/Help/RemoteControlCommands>
+use std::io::{prelude::*, Error};
+use std::net::TcpStream;
+use std::path::Path;
+
+/// Where JOSM listens for remote control commands
+const REMOTE_CONTROL: &str = "127.0.0.1:8111";
+
+/// Open each file in JOSM, then zoom to the bounds
+pub fn load(paths: &[&Path], bounds: &[f64; 4]) -> std::io::Result<()> {
+    for path in paths {
+        let path = std::fs::canonicalize(path)?;
+        request(&format!(
+            "/open_file?filename={}",
+            url_encode(&path.to_string_lossy())
+        ))?;
+    }
+    if bounds.iter().all(|x| x.is_finite()) {
+        request(&format!(
+            "/zoom?left={}&right={}&top={}&bottom={}",
+            bounds[1], bounds[3], bounds[2], bounds[0]
+        ))?;
+    }
+    Ok(())
+}
+
+/// Make a GET request to the remote control, failing unless it answers 200
+fn request(path_and_query: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(REMOTE_CONTROL)?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n",
+        path_and_query, REMOTE_CONTROL
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) == Some("200") {
+        Ok(())
+    } else {
+        Err(Error::other(format!(
+            "JOSM refused {}: {}",
+            path_and_query, status
+        )))
+    }
+}
+
+/// Percent-encode everything but the unreserved characters
+fn url_encode(src: &str) -> String {
+    let mut out = String::new();
+    for byte in src.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
@@ -0,0 +1,41 @@
+//! The core of the filter, kept apart from any file handling
+//! so it can be run (and benchmarked) on data already in memory
+use log::debug;
+use osmpbfreader::{NodeId, OsmId, OsmObj};
+use std::collections::{BTreeMap, HashSet};
+
+/// Every node with a `barrier=*` tag. A gate or bollard is good evidence
+/// the access restriction is real, so these poison the ways through them.
+pub fn barrier_nodes(data: &BTreeMap<OsmId, OsmObj>) -> HashSet<NodeId> {
+    let mut poison_nodes = HashSet::new();
+    for (id, obj) in data.iter() {
+        if let OsmObj::Node(n) = obj {
+            if n.tags.contains_key("barrier") {
+                poison_nodes.insert(id.node().expect("Broken unwrapping osmid"));
+            }
+        };
+    }
+    poison_nodes
+}
+
+/// The ways that don't share a node with any of the poison nodes
+pub fn filter_driveways<'a>(
+    data: &'a BTreeMap<OsmId, OsmObj>,
+    poison_nodes: &HashSet<NodeId>,
+) -> Vec<&'a OsmObj> {
+    data.values()
+        .filter(|obj| match obj {
+            OsmObj::Way(w) => {
+                // Look each node up rather than building a set per way
+                match w.nodes.iter().find(|n| poison_nodes.contains(n)) {
+                    Some(node) => {
+                        debug!("Way {} dropped: shares poison node {}", w.id.0, node.0);
+                        false
+                    }
+                    None => true,
+                }
+            }
+            _ => false,
+        })
+        .collect()
+}
@@ -0,0 +1,97 @@
+//! Checking the kept ways against the live OSM API, since a way may have
+//! been fixed by someone else after the extract was made
+use log::debug;
+use osmpbfreader::OsmObj;
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+use xml::reader::{EventReader, XmlEvent};
+
+/// Base URL of the OSM API
+const API: &str = "https://api.openstreetmap.org/api/0.6";
+/// Pause between requests, to stay well inside the API usage policy
+const DELAY: Duration = Duration::from_millis(500);
+
+/// Keep only the ways that are still at the version they have in the
+/// extract, with the same access tag. Ways that were deleted are dropped.
+pub fn current_ways(ways: Vec<&OsmObj>) -> Result<Vec<&OsmObj>, Box<dyn Error>> {
+    let agent = ureq::AgentBuilder::new()
+        .user_agent(concat!("accessfix/", env!("CARGO_PKG_VERSION")))
+        .build();
+    let mut current = Vec::with_capacity(ways.len());
+    for (i, obj) in ways.into_iter().enumerate() {
+        if i > 0 {
+            thread::sleep(DELAY);
+        }
+        let id = obj.id().inner_id();
+        let body = match agent.get(&format!("{}/way/{}", API, id)).call() {
+            Ok(response) => response.into_string()?,
+            Err(ureq::Error::Status(404, _)) | Err(ureq::Error::Status(410, _)) => {
+                debug!("Way {} dropped: deleted since the extract", id);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let (version, access) = parse_way(&body)?;
+        if version != obj.version() {
+            debug!(
+                "Way {} dropped: now at version {:?}, extract has {:?}",
+                id,
+                version,
+                obj.version()
+            );
+        } else if access.as_deref() != obj.tags().get("access").map(|x| x.as_str()) {
+            debug!("Way {} dropped: its access tag has changed", id);
+        } else {
+            current.push(obj);
+        }
+    }
+    Ok(current)
+}
+
+/// Pull the version and access tag out of an API response for a single way
+fn parse_way(xml: &str) -> Result<(Option<i32>, Option<String>), xml::reader::Error> {
+    let mut version = None;
+    let mut access = None;
+    for event in EventReader::from_str(xml) {
+        if let XmlEvent::StartElement {
+            name, attributes, ..
+        } = event?
+        {
+            let attr = |key: &str| {
+                attributes
+                    .iter()
+                    .find(|a| a.name.local_name == key)
+                    .map(|a| a.value.as_str())
+            };
+            match name.local_name.as_str() {
+                "way" => version = attr("version").and_then(|v| v.parse().ok()),
+                "tag" if attr("k") == Some("access") => access = attr("v").map(String::from),
+                _ => {}
+            }
+        }
+    }
+    Ok((version, access))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_version_and_access() {
+        let xml = r#"<osm version="0.6">
+            <way id="1" version="3" changeset="9">
+                <nd ref="1"/><nd ref="2"/>
+                <tag k="service" v="driveway"/>
+                <tag k="access" v="private"/>
+            </way>
+        </osm>"#;
+        assert_eq!(
+            parse_way(xml).unwrap(),
+            (Some(3), Some("private".to_string()))
+        );
+        let fixed = r#"<osm><way id="1" version="4"><tag k="highway" v="service"/></way></osm>"#;
+        assert_eq!(parse_way(fixed).unwrap(), (Some(4), None));
+    }
+}
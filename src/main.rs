@@ -1,23 +1,57 @@
-use osmpbfreader::{Node, NodeId, OsmId, OsmObj, OsmPbfReader, Ref, Relation, Tags, Way};
-use std::collections::{BTreeMap, HashSet};
-use std::io::{prelude::*, BufReader, Write};
-use std::path::Path;
-use time::OffsetDateTime;
+mod boundary;
+mod config;
+mod josm;
+mod live;
+
+use accessfix::{barrier_nodes, filter_driveways};
+use boundary::Boundary;
+use flate2::bufread::MultiGzDecoder;
+use log::{debug, info, warn, LevelFilter};
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, OsmPbfReader, Ref, Relation, Tags, Way, WayId};
+use serde::Serialize;
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, HashSet};
+use std::io::{prelude::*, BufReader, BufWriter, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use structopt::{clap::AppSettings, StructOpt};
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
 use xml::{common::XmlVersion, writer::EventWriter, writer::XmlEvent, EmitterConfig};
 
 /// Parse a file copy-pasted from the Wiki that has
 /// a big list of Amazon Logistics editors
-fn parse_amazon_editors(path: &Path) -> HashSet<String> {
+fn parse_amazon_editors(path: &Path, ignore_case: bool) -> std::io::Result<HashSet<String>> {
     // Read the file
-    let file = std::fs::File::open(path).unwrap();
-    let reader = BufReader::new(file);
+    let file = std::fs::File::open(path)?;
+    let mut reader = BufReader::new(file);
+    // Lists pulled out of archives may be gzipped, whatever they're named
+    let reader: Box<dyn BufRead> = if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    };
 
     // Organize into a HashSet
     let mut set = HashSet::new();
     for line in reader.lines() {
-        set.insert(line.unwrap().trim_end().to_string());
+        // Skip blank lines and `#` comments, whole line or trailing
+        let line = line?;
+        let name = normalize_name(line.split('#').next().unwrap_or(""), ignore_case);
+        if !name.is_empty() {
+            set.insert(name);
+        }
+    }
+    Ok(set)
+}
+
+/// Trim a user name and collapse runs of whitespace (including NBSP) to a
+/// single space, so names copied off the wiki still match
+fn normalize_name(name: &str, ignore_case: bool) -> String {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if ignore_case {
+        name.to_lowercase()
+    } else {
+        name
     }
-    set
 }
 
 fn compare_vals(p: f64, min: &mut f64, max: &mut f64) {
@@ -27,12 +61,12 @@ fn compare_vals(p: f64, min: &mut f64, max: &mut f64) {
     *max = omax;
 }
 
-fn get_bounds(data: &BTreeMap<OsmId, OsmObj>) -> [f64; 4] {
+fn get_bounds<'a>(data: impl IntoIterator<Item = &'a OsmObj>) -> [f64; 4] {
     let mut minlat = std::f64::INFINITY;
     let mut minlon = std::f64::INFINITY;
     let mut maxlat = std::f64::NEG_INFINITY;
     let mut maxlon = std::f64::NEG_INFINITY;
-    for (_, item) in data.iter() {
+    for item in data {
         match item {
             OsmObj::Node(n) => {
                 let lat = n.lat();
@@ -47,333 +81,2650 @@ fn get_bounds(data: &BTreeMap<OsmId, OsmObj>) -> [f64; 4] {
     [minlat, minlon, maxlat, maxlon]
 }
 
+/// Mean radius of the Earth in km
+const EARTH_RADIUS: f64 = 6371.0088;
+
+/// Area in km² of the bounds on a spherical Earth. Empty bounds,
+/// or ones around a single point or line, have no area.
+fn bounds_area(bounds: &[f64; 4]) -> f64 {
+    if bounds.iter().any(|x| !x.is_finite()) || bounds[0] >= bounds[2] || bounds[1] >= bounds[3] {
+        return 0.0;
+    }
+    let [minlat, minlon, maxlat, maxlon] = *bounds;
+    EARTH_RADIUS.powi(2)
+        * (maxlon - minlon).to_radians()
+        * (maxlat.to_radians().sin() - minlat.to_radians().sin())
+}
+
+/// Parse a `minlat,minlon,maxlat,maxlon` box in the same order as `get_bounds`
+fn parse_bbox(src: &str) -> Result<[f64; 4], String> {
+    let parts = src
+        .split(',')
+        .map(|x| x.trim().parse::<f64>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if parts.len() != 4 {
+        return Err("expected minlat,minlon,maxlat,maxlon".to_string());
+    }
+    if parts[0] > parts[2] || parts[1] > parts[3] {
+        return Err("minimum is larger than maximum".to_string());
+    }
+    Ok([parts[0], parts[1], parts[2], parts[3]])
+}
+
+/// Look up the (lat, lon) of each of a way's nodes, skipping any not in the map
+fn way_coords(data: &BTreeMap<OsmId, OsmObj>, way: &Way) -> Vec<(f64, f64)> {
+    way.nodes
+        .iter()
+        .filter_map(|id| match data.get(&OsmId::Node(*id)) {
+            Some(OsmObj::Node(n)) => Some((n.lat(), n.lon())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Average position of a way's nodes, counting the
+/// shared first/last node of a closed way only once
+fn centroid(coords: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let coords = match coords {
+        [first, rest @ .., last] if first == last && !rest.is_empty() => &coords[1..],
+        _ => coords,
+    };
+    if coords.is_empty() {
+        return None;
+    }
+    let n = coords.len() as f64;
+    let (lat, lon) = coords
+        .iter()
+        .fold((0.0, 0.0), |acc, p| (acc.0 + p.0, acc.1 + p.1));
+    Some((lat / n, lon / n))
+}
+
+/// Great circle distance in meters between two (lat, lon) points
+fn haversine((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * 1000.0 * a.sqrt().asin()
+}
+
+/// Length of a way in meters, or `None` if any of its nodes are missing
+fn way_length(data: &BTreeMap<OsmId, OsmObj>, way: &Way) -> Option<f64> {
+    let coords = way_coords(data, way);
+    if coords.len() != way.nodes.len() {
+        return None;
+    }
+    Some(coords.windows(2).map(|p| haversine(p[0], p[1])).sum())
+}
+
+/// How many of a way's nodes aren't in the map
+fn missing_nodes(data: &BTreeMap<OsmId, OsmObj>, way: &Way) -> usize {
+    way.nodes
+        .iter()
+        .filter(|id| !data.contains_key(&OsmId::Node(**id)))
+        .count()
+}
+
+/// The highway value of a driveway that isn't `highway=service`, which is
+/// more likely a mistagged road than a private driveway
+fn wrong_highway(way: &Way) -> Option<&str> {
+    match way.tags.get("highway").map(|x| x.as_str()) {
+        Some("service") => None,
+        highway => Some(highway.unwrap_or("(none)")),
+    }
+}
+
+/// What's wrong with a way's node list, if anything: the same node twice
+/// in a row, or a node visited more than twice (a closed way has its
+/// first node twice, which is fine)
+fn malformed_way(way: &Way) -> Option<String> {
+    if let Some(pair) = way.nodes.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Some(format!("node {} is repeated back to back", pair[0].0));
+    }
+    let mut counts = BTreeMap::new();
+    for node in &way.nodes {
+        *counts.entry(node.0).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .find(|&(_, n)| n > 2)
+        .map(|(node, n)| format!("node {} appears {} times", node, n))
+}
+
+fn in_bbox(bbox: &[f64; 4], (lat, lon): (f64, f64)) -> bool {
+    lat >= bbox[0] && lon >= bbox[1] && lat <= bbox[2] && lon <= bbox[3]
+}
+
+/// Whether a way with these coordinates passes `--bbox`: any node inside
+/// it, or with `all` (for `--bbox-inside`) every node
+fn way_in_bbox(bbox: &[f64; 4], coords: &[(f64, f64)], all: bool) -> bool {
+    if all {
+        !coords.is_empty() && coords.iter().all(|&p| in_bbox(bbox, p))
+    } else {
+        coords.iter().any(|&p| in_bbox(bbox, p))
+    }
+}
+
+/// Whether a way with these coordinates passes `--boundary`: its centroid
+/// inside it, or with `any_node` (for `--boundary-any-node`) any node
+fn way_in_boundary(boundary: &Boundary, coords: &[(f64, f64)], any_node: bool) -> bool {
+    if any_node {
+        coords.iter().any(|&(lat, lon)| boundary.contains(lat, lon))
+    } else {
+        matches!(centroid(coords), Some((lat, lon)) if boundary.contains(lat, lon))
+    }
+}
+
+/// Read a file of way ids, one per line
+fn parse_way_ids(path: &Path) -> Result<BTreeSet<OsmId>, Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut set = BTreeSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let id = line
+            .parse()
+            .map_err(|_| format!("bad way id {:?} in {}", line, path.display()))?;
+        set.insert(OsmId::Way(WayId(id)));
+    }
+    Ok(set)
+}
+
+/// Replace any directories with the .pbf files inside them
+fn expand_inputs(paths: &[PathBuf]) -> std::io::Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut found = Vec::new();
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?.path();
+                if entry.extension().and_then(|x| x.to_str()) == Some("pbf") {
+                    found.push(entry);
+                }
+            }
+            found.sort();
+            inputs.append(&mut found);
+        } else {
+            inputs.push(path.clone());
+        }
+    }
+    Ok(inputs)
+}
+
+/// Run the filter over each input and merge the results. Adjacent extracts
+/// overlap at the border, so duplicates keep whichever has the higher version.
+/// With `relations`, a second pass also picks up every relation with one of
+/// the matched ways as a member, which `get_objs_and_deps` never does since
+/// relations aren't dependencies of their members.
+fn read_inputs<F>(
+    inputs: &[PathBuf],
+    relations: bool,
+    mut pred: F,
+) -> Result<BTreeMap<OsmId, OsmObj>, Box<dyn std::error::Error>>
+where
+    F: FnMut(&OsmObj) -> bool,
+{
+    let mut merged = BTreeMap::new();
+    for path in inputs {
+        let file = open_input(path)?;
+        let mut reader = OsmPbfReader::new(file);
+        let mut scanned = 0;
+        let corrupt = |scanned, source| CorruptInput {
+            path: path.clone(),
+            scanned,
+            source,
+        };
+        let mut objs = reader
+            .get_objs_and_deps(|obj| {
+                scanned += 1;
+                pred(obj)
+            })
+            .map_err(|source| corrupt(scanned, source))?;
+        if relations {
+            let ways: HashSet<_> = objs.keys().filter(|id| id.is_way()).copied().collect();
+            reader.rewind()?;
+            for obj in reader.iter() {
+                let obj = obj.map_err(|source| corrupt(scanned, source))?;
+                if let OsmObj::Relation(r) = &obj {
+                    if r.refs.iter().any(|m| ways.contains(&m.member)) {
+                        objs.insert(obj.id(), obj);
+                    }
+                }
+            }
+        }
+        info!("Read {} objects from {}", objs.len(), path.display());
+        merge_objs(&mut merged, objs);
+    }
+    Ok(merged)
+}
+
+/// Add one input's objects to the others, keeping whichever
+/// has the higher version when an id is in both
+fn merge_objs(merged: &mut BTreeMap<OsmId, OsmObj>, objs: BTreeMap<OsmId, OsmObj>) {
+    for (id, obj) in objs {
+        match merged.entry(id) {
+            Entry::Vacant(e) => {
+                e.insert(obj);
+            }
+            Entry::Occupied(mut e) => {
+                if obj.version() > e.get().version() {
+                    e.insert(obj);
+                }
+            }
+        }
+    }
+}
+
+/// Exit code for any other error, like bad arguments or an unreadable file
+const EXIT_ERROR: i32 = 1;
+/// Exit code when an input PBF can't be read to the end
+const EXIT_CORRUPT_INPUT: i32 = 2;
+/// Exit code when the run worked but no ways were kept, so there's nothing to review
+const EXIT_NO_WAYS: i32 = 3;
+
+/// Reading a PBF failed partway through, which almost always means an
+/// interrupted download rather than a bug in the filter
+#[derive(Debug)]
+struct CorruptInput {
+    path: PathBuf,
+    /// Objects read by the first pass, the only one that sees every object.
+    /// If a later pass failed, this is the whole file.
+    scanned: usize,
+    source: osmpbfreader::Error,
+}
+
+impl std::fmt::Display for CorruptInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} appears corrupt or truncated: {} (the first pass read {} objects)",
+            self.path.display(),
+            self.source,
+            self.scanned
+        )
+    }
+}
+
+impl std::error::Error for CorruptInput {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Open the PBF, where `-` means stdin. Reading the PBF needs to seek
+/// back to the start, so stdin is first copied into a temporary file.
+fn open_input(path: &Path) -> std::io::Result<std::fs::File> {
+    if path != Path::new("-") {
+        return std::fs::File::open(path);
+    }
+    let mut file = tempfile::tempfile()?;
+    let mut stdin = BufReader::new(std::io::stdin().lock());
+    std::io::copy(&mut stdin, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Output file formats
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    /// OSM XML for loading into JOSM
+    Osm,
+    /// Tab separated way id, editor and WKT linestring
+    Wkt,
+}
+
+const FORMATS: &[&str] = &["osm", "wkt"];
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "osm" => Ok(Format::Osm),
+            "wkt" => Ok(Format::Wkt),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+/// Ways of splitting the output other than by size
+#[derive(Clone, Copy, PartialEq)]
+enum SplitBy {
+    /// One file per changeset, since an import's problems tend to repeat within it
+    Changeset,
+}
+
+const SPLIT_BYS: &[&str] = &["changeset"];
+
+impl FromStr for SplitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "changeset" => Ok(SplitBy::Changeset),
+            _ => Err(format!("unknown split {}", s)),
+        }
+    }
+}
+
+/// How a way is attributed to an Amazon editor. A PBF only carries the
+/// user of the latest version, so earlier authors can never be seen.
+#[derive(Clone, Copy, PartialEq)]
+enum Attribution {
+    /// Version 1 by an Amazon editor: they created it and nobody has touched it since
+    Creator,
+    /// Latest version by an Amazon editor, whoever created it
+    LastEditor,
+}
+
+const ATTRIBUTIONS: &[&str] = &["creator", "last-editor"];
+
+impl FromStr for Attribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "creator" => Ok(Attribution::Creator),
+            "last-editor" => Ok(Attribution::LastEditor),
+            _ => Err(format!("unknown attribution {}", s)),
+        }
+    }
+}
+
+/// Command line options
+#[derive(StructOpt)]
+#[structopt(
+    about = "Find Amazon driveways with access=private for review",
+    global_settings = &[AppSettings::AllArgsOverrideSelf]
+)]
+struct Opt {
+    /// Read options from this TOML or JSON file, keyed by their long names.
+    /// Flags on the command line win, except lists like --input which add up,
+    /// and flags set to true in the file, which can't be turned off again.
+    /// `verbose = 2` is the same as -vv.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Log each stage with -v, and every way's fate with -vv
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// PBF extract to filter, or - to read it from stdin. Can be given
+    /// several times, or be a directory of .pbf files, to merge the results.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        default_value = "private_data/new-hampshire-latest-internal.osm.pbf"
+    )]
+    input: Vec<PathBuf>,
+    /// List of Amazon Logistics editors, one per line, optionally gzipped
+    #[structopt(long, parse(from_os_str), default_value = "public_data/amazon.txt")]
+    editors: PathBuf,
+    /// Match editor names regardless of case
+    #[structopt(long)]
+    ignore_case: bool,
+    /// File to write
+    #[structopt(long, parse(from_os_str), default_value = "output.osm")]
+    output: PathBuf,
+    /// Format of the output file
+    #[structopt(long, default_value = "osm", possible_values = FORMATS)]
+    format: Format,
+    /// Which edit has to be by an Amazon editor, see `Attribution`
+    #[structopt(long, default_value = "creator", possible_values = ATTRIBUTIONS)]
+    attribution: Attribution,
+    /// Values of the access tag to match, as a comma separated list
+    #[structopt(long, use_delimiter = true, default_value = "private")]
+    access_values: Vec<String>,
+    /// Only keep ways at this version or later
+    #[structopt(long)]
+    min_version: Option<i32>,
+    /// Only keep ways at this version or earlier, so 1 keeps the ones
+    /// nobody has touched since they were imported
+    #[structopt(long)]
+    max_version: Option<i32>,
+    /// Also reject ways connected through shared nodes to a way with a barrier
+    #[structopt(long)]
+    propagate_barriers: bool,
+    /// Keep ways that reference nodes missing from the input instead of dropping them
+    #[structopt(long)]
+    allow_incomplete: bool,
+    /// Drop ways with repeated nodes instead of only warning about them
+    #[structopt(long)]
+    drop_malformed: bool,
+    /// Drop ways whose highway tag isn't `service` instead of only warning,
+    /// since they're more likely mistagged roads than private driveways
+    #[structopt(long)]
+    drop_mistagged: bool,
+    /// Drop ways shorter than this many meters
+    #[structopt(long)]
+    min_length: Option<f64>,
+    /// With --min-length, keep ways whose length can't be measured due to missing nodes
+    #[structopt(long, requires = "min-length")]
+    keep_unmeasured: bool,
+    /// Only keep ways with a node in this box, given as minlat,minlon,maxlat,maxlon
+    #[structopt(long, parse(try_from_str = parse_bbox))]
+    bbox: Option<[f64; 4]>,
+    /// With --bbox, require every node of a way to be inside the box
+    #[structopt(long, requires = "bbox")]
+    bbox_inside: bool,
+    /// Only keep ways whose centroid is inside this .poly or GeoJSON polygon
+    #[structopt(long, parse(from_os_str))]
+    boundary: Option<PathBuf>,
+    /// With --boundary, keep ways with any node inside instead of the centroid
+    #[structopt(long, requires = "boundary")]
+    boundary_any_node: bool,
+    /// Drop ways that also carry any of these tags, given as a comma separated
+    /// list of `key` or `key=value`, since they justify the restriction anyway
+    #[structopt(long, use_delimiter = true, parse(from_str = parse_veto))]
+    veto_tags: Vec<(String, Option<String>)>,
+    /// Look each kept way up on the live OSM API and drop it if it's been
+    /// edited or lost access=private since the extract was made
+    #[structopt(long)]
+    check_live: bool,
+    /// Never keep the way ids listed in this file
+    #[structopt(long, parse(from_os_str))]
+    exclude_ids: Option<PathBuf>,
+    /// Always keep the way ids listed in this file, even if they don't match
+    #[structopt(long, parse(from_os_str))]
+    include_ids: Option<PathBuf>,
+    /// Compare the kept ways against the ids in this file, such as an earlier
+    /// run's --ids-only, and print which are new and which are gone
+    #[structopt(long, parse(from_os_str))]
+    baseline: Option<PathBuf>,
+    /// With --baseline, only keep the ways that are new since it
+    #[structopt(long, requires = "baseline")]
+    only_new: bool,
+    /// Write the kept way ids to this file, one per line in ascending order,
+    /// the same format --exclude-ids and --include-ids read
+    #[structopt(long, parse(from_os_str))]
+    ids_only: Option<PathBuf>,
+    /// Write an Overpass QL query selecting the kept ways to this file
+    #[structopt(long, parse(from_os_str))]
+    overpass: Option<PathBuf>,
+    /// Instead of filtering, count every way by an Amazon editor by service and access
+    #[structopt(long)]
+    census: bool,
+    /// Instead of filtering, walk this way id through each step of the filter
+    #[structopt(long)]
+    explain: Option<i64>,
+    /// Write a CSV report with a row and review links for each kept way
+    #[structopt(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+    /// Print how many kept ways each editor is responsible for
+    #[structopt(long)]
+    stats: bool,
+    /// Print a one line JSON summary of the run to stdout, and everything
+    /// meant for people to stderr
+    #[structopt(long, conflicts_with_all = &["census", "explain"])]
+    json_summary: bool,
+    /// Write the per-editor statistics to this JSON file
+    #[structopt(long, parse(from_os_str))]
+    stats_json: Option<PathBuf>,
+    /// Write the XML without any indentation to keep it small
+    #[structopt(long)]
+    no_indent: bool,
+    /// Decimal places for node coordinates. OSM stores 7, so more is just noise
+    #[structopt(long, default_value = "7")]
+    coord_precision: usize,
+    /// Unit of indentation for the XML
+    #[structopt(long, default_value = "  ")]
+    indent_string: String,
+    /// Write ways as they would look after the edit, see --retag-remove and --retag-add
+    #[structopt(long)]
+    retag: bool,
+    /// With --retag, the key to take off each way
+    #[structopt(long, default_value = "access")]
+    retag_remove: String,
+    /// With --retag, the tag to add to each way that had the key removed
+    #[structopt(
+        long,
+        parse(try_from_str = parse_tag),
+        default_value = "fixme=verify driveway access removed by bot"
+    )]
+    retag_add: (String, String),
+    /// Also write the barrier nodes on neighbouring matched ways, for context in review
+    #[structopt(long)]
+    include_context: bool,
+    /// Leave editor names and uids out of the output, report and stats, for sharing publicly
+    #[structopt(long)]
+    anonymize: bool,
+    /// Open the output in JOSM through remote control, zoomed to the kept ways
+    #[structopt(long)]
+    josm_load: bool,
+    /// Split the output into numbered files with at most this many ways each
+    #[structopt(long)]
+    split_size: Option<usize>,
+    /// Split the output into one file per group instead, named after it
+    #[structopt(long, possible_values = SPLIT_BYS, conflicts_with = "split-size")]
+    split_by: Option<SplitBy>,
+    /// With --split-size or --split-by, write a GeoJSON index of each file's bounds here
+    #[structopt(long, parse(from_os_str))]
+    split_index: Option<PathBuf>,
+    /// Write the ways rejected for touching a barrier (or with --propagate-barriers,
+    /// for being connected to one) to this .osm file, untouched and with their
+    /// barrier nodes, for a closer look
+    #[structopt(long, parse(from_os_str))]
+    barrier_output: Option<PathBuf>,
+}
+
 /// The goal of this script is to remove access=private
 /// from ways introduced by Amazon. The steps to accomplish this are:
 /// 1. Iterate through all the ways in the PBF applying a filter.
 ///     The filter requirements are:
-///     - Created by an Amazon Logistics employee
-///     - Has the tags `service=driveway` and `access=private`
+///     - Created by an Amazon Logistics employee (or last edited by one,
+///       with `--attribution last-editor`)
+///     - Has the tags `service=driveway` and `access=private` (or another
+///       value given with `--access-values`)
 ///     - Does not have a node that has tag `barrier=*`
 /// 2. Output (somehow) to JOSM for manual review
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let amazon = parse_amazon_editors(std::path::Path::new("public_data/amazon.txt"));
-    let file = std::fs::File::open("private_data/new-hampshire-latest-internal.osm.pbf")?;
-    let mut reader = OsmPbfReader::new(file);
-    let filtered = reader.get_objs_and_deps(|element| {
-        element.is_way()
-            && element.tags().contains("service", "driveway")
-            && element.tags().contains("access", "private")
-            && element.user().is_some()
-            && element.version().unwrap_or(0) == 1
-            && amazon.contains(
-                element
-                    .user()
-                    .as_ref()
-                    .expect("Short-circuiting broke")
-                    .as_str(),
-            )
-    })?;
-    // Do a second pass to get the bad nodes
-    let mut poison_nodes = HashSet::new();
-    for (id, obj) in filtered.iter() {
-        if let OsmObj::Node(n) = obj {
-            if n.tags.contains_key("barrier") {
-                poison_nodes.insert(id.node().expect("Broken unwrapping osmid"));
+fn main() {
+    let code = match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            if e.is::<CorruptInput>() {
+                EXIT_CORRUPT_INPUT
+            } else {
+                EXIT_ERROR
             }
+        }
+    };
+    std::process::exit(code);
+}
+
+/// Do the work, returning the exit code
+fn run() -> Result<i32, Box<dyn std::error::Error>> {
+    let opt = Opt::from_iter(config::with_file_args(std::env::args_os().collect())?);
+    env_logger::Builder::new()
+        .filter_level(match opt.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            _ => LevelFilter::Debug,
+        })
+        .parse_default_env()
+        .init();
+    if let Some(path) = &opt.config {
+        info!("Read options from {}", path.display());
+    }
+    if opt.split_size == Some(0) {
+        return Err("--split-size must be at least 1".into());
+    }
+    if opt.split_index.is_some() && opt.split_size.is_none() && opt.split_by.is_none() {
+        return Err("--split-index needs --split-size or --split-by".into());
+    }
+    if opt.attribution == Attribution::Creator && matches!(opt.min_version, Some(v) if v > 1) {
+        return Err("--min-version above 1 needs --attribution last-editor".into());
+    }
+    if opt.josm_load && opt.format != Format::Osm {
+        return Err("--josm-load only works with --format osm".into());
+    }
+    let boundary = match &opt.boundary {
+        Some(path) => Some(Boundary::from_file(path)?),
+        None => None,
+    };
+    let exclude_ids = match &opt.exclude_ids {
+        Some(path) => parse_way_ids(path)?,
+        None => BTreeSet::new(),
+    };
+    let include_ids = match &opt.include_ids {
+        Some(path) => parse_way_ids(path)?,
+        None => BTreeSet::new(),
+    };
+    let baseline = match &opt.baseline {
+        Some(path) => Some(parse_way_ids(path)?),
+        None => None,
+    };
+    let amazon = parse_amazon_editors(&opt.editors, opt.ignore_case)?;
+    info!(
+        "Loaded {} editors from {}",
+        amazon.len(),
+        opt.editors.display()
+    );
+    let inputs = expand_inputs(&opt.input)?;
+    // With --json-summary, stdout is kept for the JSON alone
+    let mut human: Box<dyn Write> = if opt.json_summary {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
+    if opt.census {
+        let census = read_inputs(&inputs, false, |element| {
+            element.is_way()
+                && match element.user() {
+                    Some(user) => amazon.contains(&normalize_name(user, opt.ignore_case)),
+                    None => false,
+                }
+        })?;
+        print_census(&census);
+        return Ok(0);
+    }
+    if let Some(id) = opt.explain {
+        let target = OsmId::Way(WayId(id));
+        let data = read_inputs(&inputs, false, |element| element.id() == target)?;
+        let lists = IdLists {
+            exclude: &exclude_ids,
+            include: &include_ids,
+            baseline: baseline.as_ref(),
         };
+        print_explanation(&data, id, &opt, &amazon, boundary.as_ref(), &lists);
+        return Ok(0);
+    }
+    let filtered = read_inputs(&inputs, true, |element| {
+        include_ids.contains(&element.id()) || is_candidate(element, &opt, &amazon)
+    })?;
+    let matched_ways = filtered.values().filter(|obj| obj.is_way()).count();
+    info!("Matched {} ways", matched_ways);
+    // Do a second pass to get the bad nodes
+    let mut poison_nodes = barrier_nodes(&filtered);
+    let barrier_count = poison_nodes.len();
+    info!("Found {} poison nodes", barrier_count);
+    if opt.propagate_barriers {
+        let before = count_poisoned(&filtered, &poison_nodes);
+        propagate_poison(&filtered, &mut poison_nodes);
+        let extra = count_poisoned(&filtered, &poison_nodes) - before;
+        warn!("Barrier propagation excluded {} more ways", extra);
     }
     // Actually filter out the ways with bad nodes
-    let (mut good_ways, good_node_ids): (Vec<_>, Vec<_>) = filtered
-        .iter()
-        .filter_map(|(_, obj)| {
-            if let OsmObj::Way(w) = obj {
-                if poison_nodes
-                    .intersection(&w.nodes.iter().map(|x| *x).collect::<HashSet<_>>())
-                    .next()
-                    .is_none()
-                {
-                    Some((obj, &w.nodes))
+    let mut good_ways = filter_driveways(&filtered, &poison_nodes);
+    let mut vetoed = 0;
+    good_ways.retain(|obj| match obj {
+        OsmObj::Way(w) => match find_veto(&w.tags, &opt.veto_tags) {
+            Some(veto) => {
+                debug!("Way {} dropped: vetoed by {}", w.id.0, veto);
+                vetoed += 1;
+                false
+            }
+            None => true,
+        },
+        _ => true,
+    });
+    if !opt.veto_tags.is_empty() {
+        warn!("Vetoed {} ways", vetoed);
+    }
+    // Clipped extracts can leave ways pointing at nodes that aren't there
+    let mut incomplete = 0;
+    good_ways.retain(|obj| {
+        if let OsmObj::Way(w) = obj {
+            if missing_nodes(&filtered, w) > 0 {
+                incomplete += 1;
+                if opt.allow_incomplete {
+                    warn!("Way {} references missing nodes", w.id.0);
                 } else {
-                    None
+                    debug!("Way {} dropped: references missing nodes", w.id.0);
                 }
+                return opt.allow_incomplete;
+            }
+        }
+        true
+    });
+    if incomplete > 0 && !opt.allow_incomplete {
+        warn!("Skipped {} ways with missing nodes", incomplete);
+    }
+    // Broken geometry is worth reporting to the import team on its own
+    let mut malformed = 0;
+    good_ways.retain(|obj| {
+        if let OsmObj::Way(w) = obj {
+            if let Some(problem) = malformed_way(w) {
+                malformed += 1;
+                warn!("Way {} is malformed: {}", w.id.0, problem);
+                return !opt.drop_malformed;
+            }
+        }
+        true
+    });
+    if malformed > 0 && opt.drop_malformed {
+        info!("Dropped {} malformed ways", malformed);
+    }
+    let mut mistagged = 0;
+    good_ways.retain(|obj| {
+        if let OsmObj::Way(w) = obj {
+            if let Some(highway) = wrong_highway(w) {
+                mistagged += 1;
+                warn!("Way {} is a driveway with highway={}", w.id.0, highway);
+                return !opt.drop_mistagged;
+            }
+        }
+        true
+    });
+    if mistagged > 0 {
+        warn!(
+            "{} {} ways with a highway tag other than service",
+            if opt.drop_mistagged {
+                "Dropped"
             } else {
-                None
+                "Found"
+            },
+            mistagged
+        );
+    }
+    // Stubs only a few meters long aren't worth reviewing
+    if let Some(min_length) = opt.min_length {
+        let before = good_ways.len();
+        good_ways.retain(|obj| {
+            if let OsmObj::Way(w) = obj {
+                let length = way_length(&filtered, w);
+                let allowed = length_allowed(length, &opt);
+                if !allowed {
+                    match length {
+                        Some(length) => {
+                            debug!("Way {} dropped: only {:.1} m long", w.id.0, length)
+                        }
+                        None => debug!("Way {} dropped: length unknown", w.id.0),
+                    }
+                }
+                allowed
+            } else {
+                false
             }
-        })
-        .unzip();
-    let good_node_ids: HashSet<_> = good_node_ids.into_iter().flatten().collect();
-    // Add the nodes back in
-    let mut good_items: Vec<_> = filtered
-        .iter()
-        .filter_map(|(_, obj)| {
-            if let OsmObj::Node(n) = obj {
-                if good_node_ids.contains(&n.id) {
-                    Some(obj)
-                } else {
-                    None
+        });
+        warn!(
+            "Dropped {} ways shorter than {} m",
+            before - good_ways.len(),
+            min_length
+        );
+    }
+    // Narrow down to the area of interest
+    if let Some(bbox) = &opt.bbox {
+        good_ways.retain(|obj| {
+            if let OsmObj::Way(w) = obj {
+                let inside = way_in_bbox(bbox, &way_coords(&filtered, w), opt.bbox_inside);
+                if !inside {
+                    debug!("Way {} dropped: outside the bbox", w.id.0);
                 }
+                inside
             } else {
-                None
+                false
             }
-        })
-        .collect();
-    good_items.append(&mut good_ways);
-
-    // Turn into an osm file
-    let mut osmfile = std::fs::File::create("output.osm").unwrap();
-    let mut writer = EmitterConfig::new()
-        .perform_indent(true)
-        .create_writer(&mut osmfile);
-    writer
-        .write(XmlEvent::StartDocument {
-            version: XmlVersion::Version10,
-            encoding: Some("UTF-8"),
-            standalone: None,
-        })
-        .unwrap();
-    writer
-        .write(XmlEvent::start_element("osm").attr("version", "0.6"))
-        .unwrap();
-    let bounds = get_bounds(&filtered);
-    writer
-        .write(
-            XmlEvent::start_element("bounds")
-                .attr("minlat", &bounds[0].to_string())
-                .attr("minlon", &bounds[1].to_string())
-                .attr("maxlat", &bounds[2].to_string())
-                .attr("maxlon", &bounds[3].to_string()),
-        )
-        .unwrap();
-    writer.write(XmlEvent::end_element()).unwrap();
-    for item in good_items {
-        match item {
-            OsmObj::Node(n) => {
-                node_to_xml(&mut writer, n);
-                tags_to_xml(&mut writer, &n.tags);
-                writer.write(XmlEvent::end_element()).unwrap();
+        });
+    }
+    if let Some(boundary) = &boundary {
+        good_ways.retain(|obj| {
+            if let OsmObj::Way(w) = obj {
+                let coords = way_coords(&filtered, w);
+                let inside = way_in_boundary(boundary, &coords, opt.boundary_any_node);
+                if !inside {
+                    debug!("Way {} dropped: outside the boundary", w.id.0);
+                }
+                inside
+            } else {
+                false
             }
-            OsmObj::Way(w) => {
-                way_to_xml(&mut writer, w);
-                nd_to_xml(&mut writer, &w.nodes);
-                tags_to_xml(&mut writer, &w.tags);
-                writer.write(XmlEvent::end_element()).unwrap();
+        });
+    }
+    // Reviewed exceptions trump everything above
+    apply_id_lists(&mut good_ways, &filtered, &exclude_ids, &include_ids);
+    if opt.check_live {
+        let before = good_ways.len();
+        good_ways = live::current_ways(good_ways)?;
+        info!(
+            "Dropped {} ways that changed since the extract",
+            before - good_ways.len()
+        );
+    }
+    if let Some(baseline) = &baseline {
+        let current: BTreeSet<_> = good_ways.iter().map(|obj| obj.id()).collect();
+        let added: Vec<_> = current.difference(baseline).collect();
+        let removed: Vec<_> = baseline.difference(&current).collect();
+        writeln!(
+            human,
+            "{} ways new since the baseline, {} gone",
+            added.len(),
+            removed.len()
+        )?;
+        for id in added {
+            writeln!(human, "+ {}", id.inner_id())?;
+        }
+        for id in removed {
+            writeln!(human, "- {}", id.inner_id())?;
+        }
+        if opt.only_new {
+            good_ways.retain(|obj| is_new(obj.id(), baseline));
+        }
+    }
+    info!("Kept {} ways", good_ways.len());
+    if opt.attribution == Attribution::LastEditor {
+        let edited = good_ways
+            .iter()
+            .filter(|obj| obj.version().unwrap_or(0) > 1)
+            .count();
+        if edited > 0 {
+            warn!(
+                "{} kept ways are past version 1, so whoever created them is unknown",
+                edited
+            );
+        }
+    }
+
+    if let Some(path) = &opt.report {
+        let file = std::fs::File::create(path)?;
+        write_report(file, &good_ways, opt.anonymize)?;
+    }
+    if let Some(path) = &opt.ids_only {
+        let mut file = BufWriter::new(std::fs::File::create(path)?);
+        for obj in &good_ways {
+            writeln!(file, "{}", obj.id().inner_id())?;
+        }
+        file.flush()?;
+    }
+    if let Some(path) = &opt.overpass {
+        let ids: Vec<_> = good_ways.iter().map(|obj| obj.id().inner_id()).collect();
+        std::fs::write(path, overpass_query(&ids))?;
+    }
+    if opt.stats || opt.stats_json.is_some() {
+        let mut counts = editor_counts(&good_ways);
+        if opt.anonymize {
+            anonymize_counts(&mut counts);
+        }
+        let changesets = changeset_counts(&good_ways);
+        if opt.stats {
+            print_stats(&mut human, &counts)?;
+            print_changesets(&mut human, &changesets)?;
+        }
+        if let Some(path) = &opt.stats_json {
+            let stats = serde_json::json!({
+                "total_ways": good_ways.len(),
+                "distinct_editors": counts.len(),
+                "editors": counts
+                    .iter()
+                    .map(|(user, ways)| serde_json::json!({"user": user, "ways": ways}))
+                    .collect::<Vec<_>>(),
+                "changesets": changesets
+                    .iter()
+                    .map(|(changeset, ways)| serde_json::json!({"changeset": changeset, "ways": ways}))
+                    .collect::<Vec<_>>(),
+            });
+            let file = std::fs::File::create(path)?;
+            serde_json::to_writer_pretty(file, &stats)?;
+        }
+    }
+
+    // Turn into one or more output files
+    let xml_options = XmlOptions {
+        emitter: EmitterConfig::new()
+            .perform_indent(!opt.no_indent)
+            .indent_string(opt.indent_string.clone()),
+        retag: if opt.retag {
+            Some(Retag {
+                remove: opt.retag_remove.clone(),
+                add: opt.retag_add.clone(),
+            })
+        } else {
+            None
+        },
+        anonymize: opt.anonymize,
+        coord_precision: opt.coord_precision,
+    };
+    let chunks = split_output(&good_ways, &opt.output, opt.split_size, opt.split_by);
+    for (path, chunk) in chunks.iter() {
+        let file = std::fs::File::create(path)?;
+        match opt.format {
+            Format::Osm => write_osm(
+                file,
+                &collect_items(&filtered, chunk, opt.include_context),
+                &xml_options,
+            )?,
+            Format::Wkt => write_wkt(file, &filtered, chunk, opt.anonymize)?,
+        }
+    }
+    if let Some(path) = &opt.barrier_output {
+        // Every way the barriers rejected, so none falls between the two files
+        let barrier_ways = poisoned_ways(&filtered, &poison_nodes);
+        info!("Writing {} ways rejected by barriers", barrier_ways.len());
+        let options = XmlOptions {
+            emitter: xml_options.emitter.clone(),
+            retag: None,
+            anonymize: opt.anonymize,
+            coord_precision: opt.coord_precision,
+        };
+        let file = std::fs::File::create(path)?;
+        write_osm(
+            file,
+            &collect_items(&filtered, &barrier_ways, false),
+            &options,
+        )?;
+    }
+    if let Some(path) = &opt.split_index {
+        let index: Vec<_> = chunks
+            .iter()
+            .map(|(path, chunk)| {
+                let bounds = get_bounds(collect_items(&filtered, chunk, opt.include_context));
+                (path.as_path(), chunk.len(), bounds)
+            })
+            .collect();
+        std::fs::write(path, split_index(&index).to_string())?;
+    }
+    let bounds = get_bounds(collect_items(&filtered, &good_ways, opt.include_context));
+    writeln!(
+        human,
+        "Kept {} ways spanning about {:.1} km²",
+        good_ways.len(),
+        bounds_area(&bounds)
+    )?;
+    if opt.json_summary {
+        let summary = Summary {
+            matched_ways,
+            poison_nodes: barrier_count,
+            rejected_ways: matched_ways.saturating_sub(good_ways.len()),
+            kept_ways: good_ways.len(),
+            distinct_editors: editor_counts(&good_ways).len(),
+            bounds,
+            area_km2: bounds_area(&bounds),
+        };
+        println!("{}", serde_json::to_string(&summary)?);
+    }
+    if opt.josm_load {
+        let paths: Vec<_> = chunks.iter().map(|(path, _)| path.as_path()).collect();
+        match josm::load(&paths, &bounds) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                warn!("Couldn't reach JOSM, is it running with remote control enabled?")
             }
-            OsmObj::Relation(r) => {
-                relation_to_xml(&mut writer, r);
-                member_to_xml(&mut writer, &r.refs);
-                tags_to_xml(&mut writer, &r.tags);
-                writer.write(XmlEvent::end_element()).unwrap();
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(if good_ways.is_empty() {
+        EXIT_NO_WAYS
+    } else {
+        0
+    })
+}
+
+/// A GeoJSON FeatureCollection with each split file's bounds as a rectangle
+fn split_index(files: &[(&Path, usize, [f64; 4])]) -> serde_json::Value {
+    let features: Vec<_> = files
+        .iter()
+        .map(|(path, ways, b)| {
+            serde_json::json!({
+                "type": "Feature",
+                "properties": {
+                    "file": path.to_string_lossy(),
+                    "ways": ways,
+                },
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[
+                        [b[1], b[0]],
+                        [b[3], b[0]],
+                        [b[3], b[2]],
+                        [b[1], b[2]],
+                        [b[1], b[0]],
+                    ]],
+                },
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+/// Take out the `--exclude-ids` ways and put in the `--include-ids` ones,
+/// leaving the ways sorted by id
+fn apply_id_lists<'a>(
+    ways: &mut Vec<&'a OsmObj>,
+    filtered: &'a BTreeMap<OsmId, OsmObj>,
+    exclude_ids: &BTreeSet<OsmId>,
+    include_ids: &BTreeSet<OsmId>,
+) {
+    for id in exclude_ids.iter() {
+        if !ways.iter().any(|obj| obj.id() == *id) {
+            warn!("Excluded way {} was not in the results", id.inner_id());
+        }
+    }
+    ways.retain(|obj| {
+        let excluded = exclude_ids.contains(&obj.id());
+        if excluded {
+            debug!(
+                "Way {} dropped: listed in --exclude-ids",
+                obj.id().inner_id()
+            );
+        }
+        !excluded
+    });
+    for id in include_ids.iter() {
+        match filtered.get(id) {
+            Some(obj) => {
+                if !ways.iter().any(|x| x.id() == *id) {
+                    debug!("Way {} added: listed in --include-ids", id.inner_id());
+                    ways.push(obj);
+                }
             }
+            None => warn!("Included way {} was not found in the input", id.inner_id()),
         }
     }
-    writer.write(XmlEvent::end_element()).unwrap();
-    Ok(())
+    ways.sort_by_key(|obj| obj.id());
 }
 
-fn node_to_xml<W>(writer: &mut EventWriter<W>, node: &Node)
-where
-    W: Write,
-{
-    writer
-        .write(
-            XmlEvent::start_element("node")
-                .attr("id", &node.id.0.to_string())
-                .attr("lat", &node.lat().to_string())
-                .attr("lon", &node.lon().to_string())
-                .attr(
-                    "user",
-                    &node
-                        .user()
-                        .as_ref()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "uid",
-                    &node.uid().map(|x| x.to_string()).unwrap_or("".to_string()),
-                )
-                .attr(
-                    "visible",
-                    &node
-                        .visible()
-                        .map(|x| x.to_string())
-                        .unwrap_or("true".to_string()),
-                )
-                .attr(
-                    "version",
-                    &node
-                        .version()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "changeset",
-                    &node
-                        .changeset()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "timestamp",
-                    &node
-                        .timestamp()
-                        .map(|x| OffsetDateTime::from_unix_timestamp(x).format("%FT%H:%M:%SZ"))
-                        .unwrap_or("".to_string()),
-                ),
-        )
-        .unwrap()
+/// How many ways touch a poisoned node
+fn count_poisoned(data: &BTreeMap<OsmId, OsmObj>, poison_nodes: &HashSet<NodeId>) -> usize {
+    poisoned_ways(data, poison_nodes).len()
 }
 
-fn way_to_xml<W>(writer: &mut EventWriter<W>, node: &Way)
-where
-    W: Write,
-{
-    writer
-        .write(
-            XmlEvent::start_element("way")
-                .attr("id", &node.id.0.to_string())
-                .attr(
-                    "user",
-                    &node
-                        .user()
-                        .as_ref()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "uid",
-                    &node.uid().map(|x| x.to_string()).unwrap_or("".to_string()),
-                )
-                .attr(
-                    "visible",
-                    &node
-                        .visible()
-                        .map(|x| x.to_string())
-                        .unwrap_or("true".to_string()),
-                )
-                .attr(
-                    "version",
-                    &node
-                        .version()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "changeset",
-                    &node
-                        .changeset()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "timestamp",
-                    &node
-                        .timestamp()
-                        .map(|x| OffsetDateTime::from_unix_timestamp(x).format("%FT%H:%M:%SZ"))
-                        .unwrap_or("".to_string()),
-                ),
-        )
-        .unwrap()
+/// The ways that touch a poisoned node
+fn poisoned_ways<'a>(
+    data: &'a BTreeMap<OsmId, OsmObj>,
+    poison_nodes: &HashSet<NodeId>,
+) -> Vec<&'a OsmObj> {
+    data.values()
+        .filter(|obj| match obj {
+            OsmObj::Way(w) => w.nodes.iter().any(|n| poison_nodes.contains(n)),
+            _ => false,
+        })
+        .collect()
 }
 
-fn relation_to_xml<W>(writer: &mut EventWriter<W>, node: &Relation)
-where
-    W: Write,
-{
-    writer
-        .write(
-            XmlEvent::start_element("relation")
-                .attr("id", &node.id.0.to_string())
-                .attr(
-                    "user",
-                    &node
-                        .user()
-                        .as_ref()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "uid",
-                    &node.uid().map(|x| x.to_string()).unwrap_or("".to_string()),
-                )
-                .attr(
-                    "visible",
-                    &node
-                        .visible()
-                        .map(|x| x.to_string())
-                        .unwrap_or("true".to_string()),
-                )
-                .attr(
-                    "version",
-                    &node
-                        .version()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "changeset",
-                    &node
-                        .changeset()
-                        .map(|x| x.to_string())
-                        .unwrap_or("".to_string()),
-                )
-                .attr(
-                    "timestamp",
-                    &node
-                        .timestamp()
-                        .map(|x| OffsetDateTime::from_unix_timestamp(x).format("%FT%H:%M:%SZ"))
-                        .unwrap_or("".to_string()),
-                ),
-        )
-        .unwrap()
+/// Spread the poison from each rejected way to all of its nodes, so that
+/// segments connected to a gated one get rejected too, until nothing changes
+fn propagate_poison(data: &BTreeMap<OsmId, OsmObj>, poison_nodes: &mut HashSet<NodeId>) {
+    loop {
+        let before = poison_nodes.len();
+        for obj in data.values() {
+            if let OsmObj::Way(w) = obj {
+                if w.nodes.iter().any(|n| poison_nodes.contains(n)) {
+                    poison_nodes.extend(w.nodes.iter().copied());
+                }
+            }
+        }
+        if poison_nodes.len() == before {
+            break;
+        }
+    }
 }
 
-fn nd_to_xml<W>(writer: &mut EventWriter<W>, nds: &[NodeId])
-where
-    W: Write,
-{
-    for id in nds.iter() {
-        writer
-            .write(XmlEvent::start_element("nd").attr("ref", &id.0.to_string()))
-            .unwrap();
-        writer.write(XmlEvent::end_element()).unwrap();
+/// Pair up a set of kept ways with the nodes they need,
+/// nodes first so that the file reads in document order.
+/// Each node is only emitted once, even if several ways share it.
+/// Relations with any kept way as a member come last. Their other members
+/// usually aren't in the output, which JOSM loads as incomplete members
+/// and `write_osm` warns about.
+/// Within each type everything is sorted by id, so two runs over the same
+/// input give byte-identical files. With `context`, barrier nodes on the
+/// other matched ways that meet a kept one are added too, so the reviewer
+/// can see the gate next door.
+fn collect_items<'a>(
+    filtered: &'a BTreeMap<OsmId, OsmObj>,
+    ways: &[&'a OsmObj],
+    context: bool,
+) -> Vec<&'a OsmObj> {
+    let mut node_ids: HashSet<_> = ways
+        .iter()
+        .filter_map(|obj| {
+            if let OsmObj::Way(w) = obj {
+                Some(w.nodes.iter())
+            } else {
+                None
+            }
+        })
+        .flatten()
+        .collect();
+    if context {
+        let barriers: Vec<_> = filtered
+            .values()
+            .filter_map(|obj| match obj {
+                OsmObj::Way(w) if w.nodes.iter().any(|n| node_ids.contains(n)) => {
+                    Some(w.nodes.iter())
+                }
+                _ => None,
+            })
+            .flatten()
+            .filter(|id| match filtered.get(&OsmId::Node(**id)) {
+                Some(OsmObj::Node(n)) => n.tags.contains_key("barrier"),
+                _ => false,
+            })
+            .collect();
+        node_ids.extend(barriers);
     }
+    // Add the nodes back in, taking each id out of the set
+    // as it is used so a duplicate can never be written twice
+    let mut items: Vec<_> = filtered
+        .values()
+        .filter(|obj| match obj {
+            OsmObj::Node(n) => node_ids.remove(&n.id),
+            _ => false,
+        })
+        .collect();
+    items.extend_from_slice(ways);
+    let kept_ids: HashSet<_> = items.iter().map(|obj| obj.id()).collect();
+    items.extend(filtered.values().filter(|obj| match obj {
+        OsmObj::Relation(r) => r.refs.iter().any(|m| kept_ids.contains(&m.member)),
+        _ => false,
+    }));
+    // OsmId orders nodes before ways before relations
+    items.sort_by_key(|obj| obj.id());
+    items
 }
 
-fn tags_to_xml<W>(writer: &mut EventWriter<W>, tags: &Tags)
-where
-    W: Write,
-{
-    for (k, v) in tags.iter() {
-        writer
-            .write(
-                XmlEvent::start_element("tag")
-                    .attr("k", k.as_str())
-                    .attr("v", v.as_str()),
-            )
-            .unwrap();
-        writer.write(XmlEvent::end_element()).unwrap();
+/// Quote a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
     }
 }
 
-fn member_to_xml<W>(writer: &mut EventWriter<W>, members: &[Ref])
+/// Write one CSV row per way, with links to view it on osm.org
+/// and to load it into JOSM. Rows are grouped by changeset, since
+/// ways from one import changeset tend to share the same problems.
+fn write_report<W>(mut out: W, ways: &[&OsmObj], anonymize: bool) -> std::io::Result<()>
 where
     W: Write,
 {
-    for m in members.iter() {
-        let (kind, id) = match m.member {
-            OsmId::Node(x) => ("node", x.0),
+    writeln!(
+        out,
+        "way_id,user,access,version,changeset,timestamp,osm_url,josm_url"
+    )?;
+    let mut ways = ways.to_vec();
+    ways.sort_by_key(|obj| obj.changeset());
+    for obj in ways {
+        let id = obj.id().inner_id();
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            id,
+            csv_field(
+                obj.user()
+                    .as_ref()
+                    .filter(|_| !anonymize)
+                    .map(|x| x.as_str())
+                    .unwrap_or("")
+            ),
+            csv_field(obj.tags().get("access").map(|x| x.as_str()).unwrap_or("")),
+            obj.version().map(|x| x.to_string()).unwrap_or_default(),
+            obj.changeset().map(|x| x.to_string()).unwrap_or_default(),
+            obj.timestamp()
+                .and_then(format_timestamp)
+                .unwrap_or_default(),
+            osm_url(id),
+            josm_url(id)
+        )?;
+    }
+    Ok(())
+}
+
+fn osm_url(way_id: i64) -> String {
+    format!("https://www.openstreetmap.org/way/{}", way_id)
+}
+
+/// Link that asks a running JOSM to load just this way
+fn josm_url(way_id: i64) -> String {
+    format!("http://127.0.0.1:8111/load_object?objects=w{}", way_id)
+}
+
+/// How many ids go in one `way(id:...)` statement
+const OVERPASS_CHUNK: usize = 500;
+
+/// Build an Overpass QL query that selects exactly these ways,
+/// unioning several statements when the list is long
+fn overpass_query(ids: &[i64]) -> String {
+    let statements: Vec<_> = ids
+        .chunks(OVERPASS_CHUNK)
+        .map(|chunk| {
+            let list: Vec<_> = chunk.iter().map(|x| x.to_string()).collect();
+            format!("way(id:{});", list.join(","))
+        })
+        .collect();
+    match statements.len() {
+        1 => format!("{}\nout geom;\n", statements[0]),
+        _ => format!("(\n  {}\n);\nout geom;\n", statements.join("\n  ")),
+    }
+}
+
+/// Count the kept ways per editor, most prolific first
+fn editor_counts(ways: &[&OsmObj]) -> Vec<(String, usize)> {
+    let mut counts = BTreeMap::new();
+    for obj in ways {
+        let user = obj
+            .user()
+            .as_ref()
+            .map(|x| x.to_string())
+            .unwrap_or_default();
+        *counts.entry(user).or_insert(0) += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    // Stable sort, so ties stay in name order
+    counts.sort_by_key(|&(_, ways)| std::cmp::Reverse(ways));
+    counts
+}
+
+/// Swap each editor's name for their rank, so `--anonymize` still
+/// shows how the ways are spread without naming anyone
+fn anonymize_counts(counts: &mut [(String, usize)]) {
+    for (i, (user, _)) in counts.iter_mut().enumerate() {
+        *user = format!("editor {}", i + 1);
+    }
+}
+
+fn print_stats(out: &mut dyn Write, counts: &[(String, usize)]) -> std::io::Result<()> {
+    let width = counts
+        .iter()
+        .map(|(user, _)| user.len())
+        .max()
+        .unwrap_or(0)
+        .max("Editor".len());
+    writeln!(out, "{:<width$}  Ways", "Editor", width = width)?;
+    for (user, ways) in counts {
+        writeln!(out, "{:<width$}  {}", user, ways, width = width)?;
+    }
+    let total: usize = counts.iter().map(|(_, ways)| ways).sum();
+    writeln!(out, "{} ways from {} distinct editors", total, counts.len())
+}
+
+/// The numbers from a run, for `--json-summary`
+#[derive(Serialize)]
+struct Summary {
+    matched_ways: usize,
+    /// Barrier nodes, not counting any spread by `--propagate-barriers`
+    poison_nodes: usize,
+    rejected_ways: usize,
+    kept_ways: usize,
+    distinct_editors: usize,
+    /// `[minlat, minlon, maxlat, maxlon]`, all null when nothing was kept
+    bounds: [f64; 4],
+    area_km2: f64,
+}
+
+/// How many kept ways came from each changeset, most first
+fn changeset_counts(ways: &[&OsmObj]) -> Vec<(Option<i64>, usize)> {
+    let mut counts = BTreeMap::new();
+    for obj in ways {
+        *counts.entry(obj.changeset()).or_insert(0) += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, ways)| std::cmp::Reverse(ways));
+    counts
+}
+
+fn print_changesets(out: &mut dyn Write, counts: &[(Option<i64>, usize)]) -> std::io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "{:<10}  Ways", "Changeset")?;
+    for (changeset, ways) in counts {
+        let changeset = changeset.map_or("unknown".to_string(), |x| x.to_string());
+        writeln!(out, "{:<10}  {}", changeset, ways)?;
+    }
+    Ok(())
+}
+
+/// Summarize all Amazon ways by their `service` and `access` tags
+fn print_census(data: &BTreeMap<OsmId, OsmObj>) {
+    let mut total = 0;
+    let mut service = BTreeMap::new();
+    let mut access = BTreeMap::new();
+    for obj in data.values() {
+        if let OsmObj::Way(w) = obj {
+            total += 1;
+            let tag = |key: &str| {
+                w.tags
+                    .get(key)
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| "(none)".to_string())
+            };
+            *service.entry(tag("service")).or_insert(0) += 1;
+            *access.entry(tag("access")).or_insert(0) += 1;
+        }
+    }
+    println!("{} ways by Amazon editors", total);
+    for (key, counts) in [("service", &service), ("access", &access)].iter() {
+        let mut counts: Vec<_> = counts.iter().collect();
+        counts.sort_by_key(|&(_, n)| std::cmp::Reverse(*n));
+        println!();
+        for (value, n) in counts {
+            println!("{}={}: {}", key, value, n);
+        }
+    }
+}
+
+/// The way id files given on the command line, for `print_explanation`
+struct IdLists<'a> {
+    exclude: &'a BTreeSet<OsmId>,
+    include: &'a BTreeSet<OsmId>,
+    baseline: Option<&'a BTreeSet<OsmId>>,
+}
+
+/// Print each check the filter makes on one way, and whether it passed.
+/// Every check is the same function the filter itself calls.
+fn print_explanation(
+    data: &BTreeMap<OsmId, OsmObj>,
+    id: i64,
+    opt: &Opt,
+    amazon: &HashSet<String>,
+    boundary: Option<&Boundary>,
+    lists: &IdLists,
+) {
+    let target = OsmId::Way(WayId(id));
+    let (obj, way) = match data.get(&target) {
+        Some(obj @ OsmObj::Way(w)) => (obj, w),
+        _ => {
+            println!("Way {} is not in the input", id);
+            return;
+        }
+    };
+    let mut kept = true;
+    let mut step = |ok: bool, what: String| {
+        println!("[{}] {}", if ok { "pass" } else { "FAIL" }, what);
+        kept &= ok;
+    };
+    let service = way.tags.get("service").map(|x| x.as_str());
+    step(
+        is_driveway(&way.tags),
+        format!("service={}", service.unwrap_or("(none)")),
+    );
+    let access = way.tags.get("access").map(|x| x.as_str());
+    step(
+        access_matches(&way.tags, opt),
+        format!(
+            "access={}, looking for {}",
+            access.unwrap_or("(none)"),
+            opt.access_values.join(" or ")
+        ),
+    );
+    if let Some(veto) = find_veto(&way.tags, &opt.veto_tags) {
+        step(false, format!("vetoed by {}", veto));
+    }
+    let version = obj.version().unwrap_or(0);
+    step(
+        attribution_allowed(version, opt),
+        match opt.attribution {
+            Attribution::LastEditor => format!("version {}, the last editor counts", version),
+            Attribution::Creator => format!(
+                "version {}, the creator is only known at version 1",
+                version
+            ),
+        },
+    );
+    if opt.min_version.is_some() || opt.max_version.is_some() {
+        step(
+            version_allowed(version, opt),
+            format!("version {} within --min-version/--max-version", version),
+        );
+    }
+    step(
+        amazon_editor(obj.user(), amazon, opt),
+        match obj.user() {
+            Some(user) => format!("user {:?} checked against {}", user, opt.editors.display()),
+            None => "no user recorded".to_string(),
+        },
+    );
+    let barriers: Vec<_> = way
+        .nodes
+        .iter()
+        .filter_map(|node| match data.get(&OsmId::Node(*node)) {
+            Some(OsmObj::Node(n)) => n.tags.get("barrier").map(|barrier| (node, barrier)),
+            _ => None,
+        })
+        .collect();
+    for (node, barrier) in &barriers {
+        step(false, format!("node {} has barrier={}", node.0, barrier));
+    }
+    if barriers.is_empty() {
+        step(
+            true,
+            format!("none of its {} nodes is a barrier", way.nodes.len()),
+        );
+    }
+    let missing = missing_nodes(data, way);
+    if missing > 0 {
+        step(
+            opt.allow_incomplete,
+            format!(
+                "{} of {} nodes missing from the input",
+                missing,
+                way.nodes.len()
+            ),
+        );
+    }
+    match malformed_way(way) {
+        Some(problem) => step(!opt.drop_malformed, format!("malformed, {}", problem)),
+        None => step(true, "no repeated nodes".to_string()),
+    }
+    match wrong_highway(way) {
+        Some(highway) => step(
+            !opt.drop_mistagged,
+            format!("highway={}, not service", highway),
+        ),
+        None => step(true, "highway=service".to_string()),
+    }
+    if let Some(min_length) = opt.min_length {
+        let length = way_length(data, way);
+        step(
+            length_allowed(length, opt),
+            match length {
+                Some(length) => format!("{:.1} m long, at least {} m needed", length, min_length),
+                None => "length unknown".to_string(),
+            },
+        );
+    }
+    let coords = way_coords(data, way);
+    if let Some(bbox) = &opt.bbox {
+        step(
+            way_in_bbox(bbox, &coords, opt.bbox_inside),
+            "within the bbox".to_string(),
+        );
+    }
+    if let Some(boundary) = boundary {
+        step(
+            way_in_boundary(boundary, &coords, opt.boundary_any_node),
+            "within the boundary".to_string(),
+        );
+    }
+    if lists.exclude.contains(&target) {
+        step(false, "listed in --exclude-ids".to_string());
+    }
+    if opt.propagate_barriers {
+        println!(
+            "Barriers spread from neighbouring ways aren't traced here, run with -vv for those"
+        );
+    }
+    let mut kept = if lists.include.contains(&target) {
+        println!("Listed in --include-ids, so kept regardless");
+        true
+    } else {
+        kept
+    };
+    // --only-new comes after --include-ids, so it can still drop the way
+    if opt.only_new {
+        if let Some(baseline) = lists.baseline {
+            let new = is_new(target, baseline);
+            println!(
+                "[{}] new since the baseline",
+                if new { "pass" } else { "FAIL" }
+            );
+            kept &= new;
+        }
+    }
+    println!(
+        "Way {} would be {}",
+        id,
+        if kept { "kept" } else { "dropped" }
+    );
+}
+
+/// The file each kept way goes to, with `--split-size` or `--split-by`
+fn split_output<'a>(
+    ways: &[&'a OsmObj],
+    output: &Path,
+    split_size: Option<usize>,
+    split_by: Option<SplitBy>,
+) -> Vec<(PathBuf, Vec<&'a OsmObj>)> {
+    match (split_size, split_by) {
+        (Some(size), _) => ways
+            .chunks(size)
+            .enumerate()
+            .map(|(i, chunk)| (chunk_path(output, i + 1), chunk.to_vec()))
+            .collect(),
+        (None, Some(SplitBy::Changeset)) => {
+            let mut groups = BTreeMap::new();
+            for obj in ways {
+                groups
+                    .entry(obj.changeset())
+                    .or_insert_with(Vec::new)
+                    .push(*obj);
+            }
+            groups
+                .into_iter()
+                .map(|(changeset, group)| {
+                    let label = changeset.map_or("unknown".to_string(), |x| x.to_string());
+                    (labelled_path(output, &label), group)
+                })
+                .collect()
+        }
+        (None, None) => vec![(output.to_path_buf(), ways.to_vec())],
+    }
+}
+
+/// Number a split output file, so `output.osm` becomes `output_001.osm`
+fn chunk_path(base: &Path, index: usize) -> PathBuf {
+    labelled_path(base, &format!("{:03}", index))
+}
+
+/// Name a split output file, so `output.osm` becomes `output_<label>.osm`
+fn labelled_path(base: &Path, label: &str) -> PathBuf {
+    let stem = base
+        .file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "output".to_string());
+    let name = match base.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, label, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, label),
+    };
+    base.with_file_name(name)
+}
+
+/// Settings for the osm XML output
+struct XmlOptions {
+    emitter: EmitterConfig,
+    /// Tag changes to make on the ways, to show the proposed end state
+    retag: Option<Retag>,
+    /// Leave the user and uid off every element
+    anonymize: bool,
+    /// Decimal places to write node coordinates with
+    coord_precision: usize,
+}
+
+/// Take one key off a way and put another tag on in its place
+struct Retag {
+    remove: String,
+    add: (String, String),
+}
+
+/// Parse a `key=value` tag
+fn parse_tag(src: &str) -> Result<(String, String), String> {
+    let mut parts = src.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(k), Some(v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => Err("expected key=value".to_string()),
+    }
+}
+
+/// Whether a way is a driveway at all
+fn is_driveway(tags: &Tags) -> bool {
+    tags.contains("service", "driveway")
+}
+
+/// Whether the access tag is one of `--access-values`
+fn access_matches(tags: &Tags, opt: &Opt) -> bool {
+    opt.access_values
+        .iter()
+        .any(|value| tags.contains("access", value))
+}
+
+/// Whether the version says the user is the one `--attribution` asks for
+fn attribution_allowed(version: i32, opt: &Opt) -> bool {
+    opt.attribution == Attribution::LastEditor || version == 1
+}
+
+/// Whether the user is on the list of Amazon editors
+fn amazon_editor(user: &Option<String>, amazon: &HashSet<String>, opt: &Opt) -> bool {
+    match user {
+        Some(user) => amazon.contains(&normalize_name(user, opt.ignore_case)),
+        None => false,
+    }
+}
+
+/// The first pass of the filter: an Amazon driveway with a matching access tag
+fn is_candidate(obj: &OsmObj, opt: &Opt, amazon: &HashSet<String>) -> bool {
+    let version = obj.version().unwrap_or(0);
+    obj.is_way()
+        && is_driveway(obj.tags())
+        && access_matches(obj.tags(), opt)
+        && attribution_allowed(version, opt)
+        && version_allowed(version, opt)
+        && amazon_editor(obj.user(), amazon, opt)
+}
+
+/// Whether a way is long enough for `--min-length`, given its length
+/// if it could be measured
+fn length_allowed(length: Option<f64>, opt: &Opt) -> bool {
+    match (opt.min_length, length) {
+        (None, _) => true,
+        (Some(min_length), Some(length)) => length >= min_length,
+        (Some(_), None) => opt.keep_unmeasured,
+    }
+}
+
+/// Whether a way is missing from `--baseline`, for `--only-new`
+fn is_new(id: OsmId, baseline: &BTreeSet<OsmId>) -> bool {
+    !baseline.contains(&id)
+}
+
+/// Whether a version is inside `--min-version` and `--max-version`
+fn version_allowed(version: i32, opt: &Opt) -> bool {
+    !matches!(opt.min_version, Some(min) if version < min)
+        && !matches!(opt.max_version, Some(max) if version > max)
+}
+
+/// Parse a `--veto-tags` entry, either `key` or `key=value`
+fn parse_veto(src: &str) -> (String, Option<String>) {
+    match parse_tag(src) {
+        Ok((k, v)) => (k, Some(v)),
+        Err(_) => (src.to_string(), None),
+    }
+}
+
+/// The first veto tag present on a way, written out for logging
+fn find_veto(tags: &Tags, vetoes: &[(String, Option<String>)]) -> Option<String> {
+    vetoes.iter().find_map(|(k, v)| match v {
+        Some(v) if tags.contains(k, v) => Some(format!("{}={}", k, v)),
+        None if tags.contains_key(k.as_str()) => Some(k.to_string()),
+        _ => None,
+    })
+}
+
+/// Write the items as an osm document with bounds covering them,
+/// or no bounds at all if there are no nodes to cover
+fn write_osm<W>(out: W, items: &[&OsmObj], options: &XmlOptions) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    let mut writer = options.emitter.clone().create_writer(out);
+    writer.write(XmlEvent::StartDocument {
+        version: XmlVersion::Version10,
+        encoding: Some("UTF-8"),
+        standalone: None,
+    })?;
+    writer.write(XmlEvent::start_element("osm").attr("version", "0.6"))?;
+    let bounds = get_bounds(items.iter().copied());
+    if bounds.iter().all(|x| x.is_finite()) {
+        // Rounded like the node coordinates, so every node stays inside
+        let bounds: Vec<_> = bounds
+            .iter()
+            .map(|x| format!("{:.*}", options.coord_precision, x))
+            .collect();
+        writer.write(
+            XmlEvent::start_element("bounds")
+                .attr("minlat", &bounds[0])
+                .attr("minlon", &bounds[1])
+                .attr("maxlat", &bounds[2])
+                .attr("maxlon", &bounds[3]),
+        )?;
+        writer.write(XmlEvent::end_element())?;
+    }
+    let ids: HashSet<_> = items.iter().map(|obj| obj.id()).collect();
+    for item in items {
+        if let OsmObj::Relation(r) = item {
+            let missing = missing_members(r, &ids);
+            if missing > 0 {
+                warn!(
+                    "Relation {} is written with {} of its {} members missing",
+                    r.id.0,
+                    missing,
+                    r.refs.len()
+                );
+            }
+        }
+        obj_to_xml(&mut writer, item, options)?;
+    }
+    writer.write(XmlEvent::end_element())
+}
+
+/// How many of a relation's members aren't among the ids written,
+/// which JOSM shows as incomplete members
+fn missing_members(relation: &Relation, ids: &HashSet<OsmId>) -> usize {
+    relation
+        .refs
+        .iter()
+        .filter(|m| !ids.contains(&m.member))
+        .count()
+}
+
+/// Write a complete element for the object, children and all
+fn obj_to_xml<W>(
+    writer: &mut EventWriter<W>,
+    obj: &OsmObj,
+    options: &XmlOptions,
+) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    let metadata = metadata(obj, options.anonymize);
+    match obj {
+        OsmObj::Node(n) => {
+            node_to_xml(writer, n, &metadata, options.coord_precision)?;
+            tags_to_xml(writer, &n.tags)?;
+        }
+        OsmObj::Way(w) => {
+            way_to_xml(writer, w, &metadata)?;
+            nd_to_xml(writer, &w.nodes)?;
+            match &options.retag {
+                Some(retag) => retagged_to_xml(writer, &w.tags, retag)?,
+                None => tags_to_xml(writer, &w.tags)?,
+            }
+        }
+        OsmObj::Relation(r) => {
+            relation_to_xml(writer, r, &metadata)?;
+            member_to_xml(writer, &r.refs)?;
+            tags_to_xml(writer, &r.tags)?;
+        }
+    }
+    writer.write(XmlEvent::end_element())
+}
+
+/// The timestamp layout OSM uses, e.g. `2023-01-02T03:04:05Z`
+const TIMESTAMP_FORMAT: &[FormatItem] =
+    format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]Z");
+
+/// Format a unix timestamp, or `None` if it is out of range
+/// (which only happens with corrupt data)
+fn format_timestamp(timestamp: i64) -> Option<String> {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()?
+        .format(TIMESTAMP_FORMAT)
+        .ok()
+}
+
+/// Escape a field for PostgreSQL's text `COPY` format
+fn copy_field(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Write one `way_id<TAB>editor<TAB>LINESTRING(...)` line per way, ready
+/// for a PostGIS `COPY ... FROM` in the default text format, which is
+/// why there's no header row. Closed ways stay linestrings since
+/// driveways aren't areas.
+fn write_wkt<W>(
+    mut out: W,
+    filtered: &BTreeMap<OsmId, OsmObj>,
+    ways: &[&OsmObj],
+    anonymize: bool,
+) -> std::io::Result<()>
+where
+    W: Write,
+{
+    for obj in ways {
+        if let OsmObj::Way(w) = obj {
+            let coords = way_coords(filtered, w);
+            if coords.len() < 2 {
+                warn!("Way {} has too few nodes for a linestring", w.id.0);
+                continue;
+            }
+            if coords.len() < w.nodes.len() {
+                warn!(
+                    "Way {} is missing {} of its nodes, its linestring skips them",
+                    w.id.0,
+                    w.nodes.len() - coords.len()
+                );
+            }
+            let points: Vec<_> = coords
+                .iter()
+                .map(|(lat, lon)| format!("{} {}", lon, lat))
+                .collect();
+            writeln!(
+                out,
+                "{}\t{}\tLINESTRING({})",
+                w.id.0,
+                copy_field(
+                    w.user()
+                        .as_ref()
+                        .filter(|_| !anonymize)
+                        .map(|x| x.as_str())
+                        .unwrap_or("")
+                ),
+                points.join(", ")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The user, version and so on of an element. Anything the source
+/// doesn't have is left out rather than written empty, since consumers
+/// (the upload API included) reject an empty `version` or `changeset`.
+fn metadata(obj: &OsmObj, anonymize: bool) -> Vec<(&'static str, String)> {
+    let mut attrs = Vec::new();
+    if !anonymize {
+        if let Some(user) = obj.user() {
+            attrs.push(("user", user.to_string()));
+        }
+        if let Some(uid) = obj.uid() {
+            attrs.push(("uid", uid.to_string()));
+        }
+    }
+    attrs.push(("visible", obj.visible().unwrap_or(true).to_string()));
+    if let Some(version) = obj.version() {
+        attrs.push(("version", version.to_string()));
+    }
+    if let Some(changeset) = obj.changeset() {
+        attrs.push(("changeset", changeset.to_string()));
+    }
+    if let Some(timestamp) = obj.timestamp().and_then(format_timestamp) {
+        attrs.push(("timestamp", timestamp));
+    }
+    attrs
+}
+
+fn node_to_xml<W>(
+    writer: &mut EventWriter<W>,
+    node: &Node,
+    metadata: &[(&str, String)],
+    precision: usize,
+) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    let id = node.id.0.to_string();
+    let lat = format!("{:.*}", precision, node.lat());
+    let lon = format!("{:.*}", precision, node.lon());
+    let mut element = XmlEvent::start_element("node")
+        .attr("id", &id)
+        .attr("lat", &lat)
+        .attr("lon", &lon);
+    for (name, value) in metadata {
+        element = element.attr(*name, value);
+    }
+    writer.write(element)
+}
+
+fn way_to_xml<W>(
+    writer: &mut EventWriter<W>,
+    way: &Way,
+    metadata: &[(&str, String)],
+) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    let id = way.id.0.to_string();
+    let mut element = XmlEvent::start_element("way").attr("id", &id);
+    for (name, value) in metadata {
+        element = element.attr(*name, value);
+    }
+    writer.write(element)
+}
+
+fn relation_to_xml<W>(
+    writer: &mut EventWriter<W>,
+    relation: &Relation,
+    metadata: &[(&str, String)],
+) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    let id = relation.id.0.to_string();
+    let mut element = XmlEvent::start_element("relation").attr("id", &id);
+    for (name, value) in metadata {
+        element = element.attr(*name, value);
+    }
+    writer.write(element)
+}
+
+fn nd_to_xml<W>(writer: &mut EventWriter<W>, nds: &[NodeId]) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    for id in nds.iter() {
+        writer.write(XmlEvent::start_element("nd").attr("ref", &id.0.to_string()))?;
+        writer.write(XmlEvent::end_element())?;
+    }
+    Ok(())
+}
+
+fn tags_to_xml<W>(writer: &mut EventWriter<W>, tags: &Tags) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    for (k, v) in tags.iter() {
+        writer.write(
+            XmlEvent::start_element("tag")
+                .attr("k", k.as_str())
+                .attr("v", v.as_str()),
+        )?;
+        writer.write(XmlEvent::end_element())?;
+    }
+    Ok(())
+}
+
+/// Like `tags_to_xml`, but with the tag changes applied
+/// if the key to remove is there
+fn retagged_to_xml<W>(
+    writer: &mut EventWriter<W>,
+    tags: &Tags,
+    retag: &Retag,
+) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    let mut tags = tags.clone();
+    if tags.remove(&retag.remove).is_some() {
+        tags.insert(retag.add.0.clone(), retag.add.1.clone());
+    }
+    tags_to_xml(writer, &tags)
+}
+
+fn member_to_xml<W>(writer: &mut EventWriter<W>, members: &[Ref]) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+{
+    for m in members.iter() {
+        let (kind, id) = match m.member {
+            OsmId::Node(x) => ("node", x.0),
             OsmId::Way(x) => ("way", x.0),
             OsmId::Relation(x) => ("relation", x.0),
         };
-        writer
-            .write(
-                XmlEvent::start_element("member")
-                    .attr("type", kind)
-                    .attr("ref", &id.to_string())
-                    .attr("role", m.role.as_str()),
+        writer.write(
+            XmlEvent::start_element("member")
+                .attr("type", kind)
+                .attr("ref", &id.to_string())
+                .attr("role", m.role.as_str()),
+        )?;
+        writer.write(XmlEvent::end_element())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osmpbfreader::{Info, RelationId, WayId};
+
+    fn node(id: i64) -> OsmObj {
+        OsmObj::Node(Node {
+            id: NodeId(id),
+            tags: Tags::new(),
+            decimicro_lat: 430_000_000 + id as i32,
+            decimicro_lon: -715_000_000 - id as i32,
+            info: Info::default(),
+        })
+    }
+
+    fn way(id: i64, nodes: &[i64]) -> OsmObj {
+        OsmObj::Way(Way {
+            id: WayId(id),
+            tags: Tags::new(),
+            nodes: nodes.iter().map(|&x| NodeId(x)).collect(),
+            info: Info::default(),
+        })
+    }
+
+    fn relation(id: i64, ways: &[i64]) -> OsmObj {
+        OsmObj::Relation(Relation {
+            id: RelationId(id),
+            tags: Tags::new(),
+            refs: ways
+                .iter()
+                .map(|&x| Ref {
+                    member: OsmId::Way(WayId(x)),
+                    role: "house".to_string(),
+                })
+                .collect(),
+            info: Info::default(),
+        })
+    }
+
+    /// A node and a way with every attribute filled in,
+    /// and tag values that need escaping
+    fn detailed_objs() -> (OsmObj, OsmObj) {
+        let info = Info {
+            version: Some(1),
+            timestamp: Some(1_672_628_645),
+            changeset: Some(987_654),
+            uid: Some(42),
+            user: Some("mapper".to_string()),
+            visible: Some(true),
+        };
+        let mut tags = Tags::new();
+        tags.insert("barrier".to_string(), "gate".to_string());
+        tags.insert("name".to_string(), "Tom & Jerry's \"<gate>\"".to_string());
+        let node = OsmObj::Node(Node {
+            id: NodeId(1),
+            tags,
+            decimicro_lat: 431_234_567,
+            decimicro_lon: -715_432_100,
+            info: info.clone(),
+        });
+        let mut tags = Tags::new();
+        tags.insert("access".to_string(), "private".to_string());
+        tags.insert("highway".to_string(), "service".to_string());
+        tags.insert("service".to_string(), "driveway".to_string());
+        let way = OsmObj::Way(Way {
+            id: WayId(10),
+            tags,
+            nodes: vec![NodeId(1), NodeId(2)],
+            info,
+        });
+        (node, way)
+    }
+
+    fn options(retag: Option<Retag>) -> XmlOptions {
+        XmlOptions {
+            emitter: EmitterConfig::new().perform_indent(true),
+            retag,
+            anonymize: false,
+            coord_precision: 7,
+        }
+    }
+
+    /// Write a single element the way `write_osm` would, minus the document
+    fn fragment(obj: &OsmObj) -> String {
+        fragment_with(obj, &options(None))
+    }
+
+    fn fragment_with(obj: &OsmObj, options: &XmlOptions) -> String {
+        let mut out = Vec::new();
+        let mut writer = EmitterConfig::new()
+            .perform_indent(true)
+            .write_document_declaration(false)
+            .create_writer(&mut out);
+        obj_to_xml(&mut writer, obj, options).unwrap();
+        drop(writer);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn node_matches_golden() {
+        let (node, _) = detailed_objs();
+        assert_eq!(
+            fragment(&node),
+            include_str!("../tests/fixtures/node.xml").trim_end()
+        );
+    }
+
+    #[test]
+    fn way_matches_golden() {
+        let (_, way) = detailed_objs();
+        assert_eq!(
+            fragment(&way),
+            include_str!("../tests/fixtures/way.xml").trim_end()
+        );
+    }
+
+    #[test]
+    fn retag_swaps_access_for_fixme() {
+        let (_, way) = detailed_objs();
+        let retag = Retag {
+            remove: "access".to_string(),
+            add: parse_tag("fixme=check access").unwrap(),
+        };
+        let xml = fragment_with(&way, &options(Some(retag)));
+        assert!(!xml.contains("k=\"access\""));
+        assert!(xml.contains("<tag k=\"fixme\" v=\"check access\" />"));
+        assert!(xml.contains("<tag k=\"service\" v=\"driveway\" />"));
+    }
+
+    #[test]
+    fn anonymize_leaves_out_user() {
+        let (_, way) = detailed_objs();
+        let mut options = options(None);
+        options.anonymize = true;
+        let xml = fragment_with(&way, &options);
+        assert!(!xml.contains("mapper"));
+        assert!(!xml.contains("user="));
+        assert!(!xml.contains("uid="));
+        assert!(xml.contains("version=\"1\""));
+
+        let mut report = Vec::new();
+        write_report(&mut report, &[&way], true).unwrap();
+        assert!(!String::from_utf8(report).unwrap().contains("mapper"));
+    }
+
+    #[test]
+    fn anonymized_stats_have_no_names() {
+        let (_, way) = detailed_objs();
+        let mut counts = editor_counts(&[&way, &way]);
+        anonymize_counts(&mut counts);
+        assert_eq!(counts, vec![("editor 1".to_string(), 2)]);
+        let mut out = Vec::new();
+        print_stats(&mut out, &counts).unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("mapper"));
+    }
+
+    #[test]
+    fn coordinates_are_rounded() {
+        let (node, _) = detailed_objs();
+        let mut options = options(None);
+        options.coord_precision = 2;
+        let xml = fragment_with(&node, &options);
+        assert!(xml.contains("lat=\"43.12\" lon=\"-71.54\""));
+
+        let mut out = Vec::new();
+        write_osm(&mut out, &[&node], &options).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(xml.contains(
+            "<bounds minlat=\"43.12\" minlon=\"-71.54\" maxlat=\"43.12\" maxlon=\"-71.54\""
+        ));
+    }
+
+    #[test]
+    fn missing_metadata_is_left_out() {
+        let xml = fragment(&node(1));
+        for attr in &["user=", "uid=", "version=", "changeset=", "timestamp="] {
+            assert!(!xml.contains(attr), "{} in {}", attr, xml);
+        }
+        assert!(xml.contains("visible=\"true\""));
+    }
+
+    fn to_map(objs: Vec<OsmObj>) -> BTreeMap<OsmId, OsmObj> {
+        objs.into_iter().map(|obj| (obj.id(), obj)).collect()
+    }
+
+    #[test]
+    fn wkt_lines_for_copy() {
+        let mut way = way(10, &[1, 2, 3]);
+        if let OsmObj::Way(w) = &mut way {
+            w.info.user = Some("tab\there\\".to_string());
+        }
+        let filtered = to_map(vec![node(1), node(2), way]);
+        let ways: Vec<_> = filtered.values().filter(|obj| obj.is_way()).collect();
+        let mut out = Vec::new();
+        write_wkt(&mut out, &filtered, &ways, false).unwrap();
+        let wkt = String::from_utf8(out).unwrap();
+        // One line, no header, and node 3 is left out of the linestring
+        assert_eq!(wkt.lines().count(), 1);
+        assert!(wkt.starts_with("10\ttab\\there\\\\\tLINESTRING(-71.5000001 43.0000001, -71.5"));
+        assert_eq!(wkt.matches(',').count(), 1);
+    }
+
+    #[test]
+    fn timestamp_is_rfc3339() {
+        assert_eq!(
+            format_timestamp(1_672_628_645).as_deref(),
+            Some("2023-01-02T03:04:05Z")
+        );
+        assert_eq!(format_timestamp(i64::MAX), None);
+    }
+
+    #[test]
+    fn area_of_bounds() {
+        assert_eq!(bounds_area(&[43.0, -71.0, 43.0, -71.0]), 0.0);
+        assert_eq!(bounds_area(&get_bounds(Vec::new())), 0.0);
+        // One degree square at the equator is about 111 km on a side
+        let area = bounds_area(&[0.0, 0.0, 1.0, 1.0]);
+        assert!((area - 12_364.0).abs() < 5.0, "{}", area);
+    }
+
+    #[test]
+    fn poison_spreads_along_connected_ways() {
+        let filtered = to_map(vec![
+            way(10, &[1, 2]),
+            way(11, &[2, 3]),
+            way(12, &[3, 4]),
+            way(13, &[5, 6]),
+        ]);
+        let mut poison_nodes: HashSet<_> = vec![NodeId(1)].into_iter().collect();
+        assert_eq!(count_poisoned(&filtered, &poison_nodes), 1);
+        propagate_poison(&filtered, &mut poison_nodes);
+        assert_eq!(count_poisoned(&filtered, &poison_nodes), 3);
+        assert!(!poison_nodes.contains(&NodeId(5)));
+    }
+
+    #[test]
+    fn id_lists_override_the_filter() {
+        let filtered = to_map(vec![way(10, &[1]), way(11, &[2]), way(12, &[3])]);
+        let ids =
+            |ids: &[i64]| -> BTreeSet<_> { ids.iter().map(|&x| OsmId::Way(WayId(x))).collect() };
+        let mut ways = vec![
+            &filtered[&OsmId::Way(WayId(12))],
+            &filtered[&OsmId::Way(WayId(11))],
+        ];
+        // 13 isn't in the input, so it's only warned about
+        apply_id_lists(&mut ways, &filtered, &ids(&[11]), &ids(&[10, 12, 13]));
+        let kept: Vec<_> = ways.iter().map(|obj| obj.id()).collect();
+        assert_eq!(kept, ids(&[10, 12]).into_iter().collect::<Vec<_>>());
+
+        // Included ways win over excluded ones
+        apply_id_lists(&mut ways, &filtered, &ids(&[10]), &ids(&[10]));
+        assert_eq!(ways.len(), 2);
+    }
+
+    #[test]
+    fn barrier_output_gets_propagated_ways() {
+        let mut gate = node(1);
+        if let OsmObj::Node(n) = &mut gate {
+            n.tags.insert("barrier".into(), "gate".into());
+        }
+        let filtered = to_map(vec![
+            gate,
+            node(2),
+            node(3),
+            node(4),
+            way(10, &[1, 2]),
+            way(11, &[2, 3]),
+            way(12, &[4]),
+        ]);
+        let ids = |poison_nodes: &HashSet<NodeId>| -> Vec<_> {
+            poisoned_ways(&filtered, poison_nodes)
+                .iter()
+                .map(|obj| obj.id().inner_id())
+                .collect()
+        };
+        let mut poison_nodes = barrier_nodes(&filtered);
+        assert_eq!(ids(&poison_nodes), vec![10]);
+        // Way 11 is dropped from the main output by propagation,
+        // so it has to go in the barrier file instead
+        propagate_poison(&filtered, &mut poison_nodes);
+        assert_eq!(ids(&poison_nodes), vec![10, 11]);
+        let kept: Vec<_> = filter_driveways(&filtered, &poison_nodes)
+            .iter()
+            .map(|obj| obj.id().inner_id())
+            .collect();
+        assert_eq!(kept, vec![12]);
+    }
+
+    #[test]
+    fn empty_output_has_no_bounds() {
+        let mut out = Vec::new();
+        write_osm(&mut out, &[], &options(None)).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(!xml.contains("<bounds"), "{}", xml);
+        assert!(!xml.contains("inf"), "{}", xml);
+
+        let filtered = to_map(vec![node(1), node(2), way(10, &[1, 2])]);
+        let ways: Vec<_> = filtered.values().filter(|obj| obj.is_way()).collect();
+        let mut out = Vec::new();
+        write_osm(
+            &mut out,
+            &collect_items(&filtered, &ways, false),
+            &options(None),
+        )
+        .unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("<bounds minlat="));
+    }
+
+    #[test]
+    fn editor_names_are_normalized() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, " Jane Doe\u{a0}\nsomeone  else").unwrap();
+        let editors = parse_amazon_editors(file.path(), false).unwrap();
+        assert!(editors.contains(&normalize_name("Jane Doe", false)));
+        assert!(editors.contains(&normalize_name("someone else ", false)));
+        assert!(!editors.contains(&normalize_name("jane doe", false)));
+        let editors = parse_amazon_editors(file.path(), true).unwrap();
+        assert!(editors.contains(&normalize_name("JANE DOE", true)));
+    }
+
+    #[test]
+    fn editor_list_comments_and_blanks() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "# Amazon Logistics editors\n\n  \nJane Doe  # joined 2021\n"
+        )
+        .unwrap();
+        let editors = parse_amazon_editors(file.path(), false).unwrap();
+        assert_eq!(editors.into_iter().collect::<Vec<_>>(), vec!["Jane Doe"]);
+    }
+
+    #[test]
+    fn gzipped_editor_list() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder =
+            flate2::write::GzEncoder::new(file.reopen().unwrap(), flate2::Compression::default());
+        writeln!(encoder, "Jane Doe").unwrap();
+        encoder.finish().unwrap();
+        let editors = parse_amazon_editors(file.path(), false).unwrap();
+        assert_eq!(editors.into_iter().collect::<Vec<_>>(), vec!["Jane Doe"]);
+    }
+
+    #[test]
+    fn veto_tags_match_key_or_pair() {
+        let vetoes: Vec<_> = ["locked", "motor_vehicle=private"]
+            .iter()
+            .map(|x| parse_veto(x))
+            .collect();
+        let mut tags = Tags::new();
+        tags.insert("motor_vehicle".into(), "yes".into());
+        assert_eq!(find_veto(&tags, &vetoes), None);
+        tags.insert("motor_vehicle".into(), "private".into());
+        assert_eq!(
+            find_veto(&tags, &vetoes).as_deref(),
+            Some("motor_vehicle=private")
+        );
+        tags.insert("locked".into(), "no".into());
+        assert_eq!(find_veto(&tags, &vetoes).as_deref(), Some("locked"));
+    }
+
+    #[test]
+    fn split_by_size() {
+        let filtered = to_map(vec![
+            node(1),
+            node(2),
+            node(3),
+            way(10, &[1, 2]),
+            way(11, &[2, 3]),
+            way(12, &[3]),
+        ]);
+        let ways: Vec<_> = filtered.values().filter(|obj| obj.is_way()).collect();
+        let chunks = split_output(&ways, Path::new("out/review.osm"), Some(2), None);
+        let names: Vec<_> = chunks.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            names,
+            vec![
+                PathBuf::from("out/review_001.osm"),
+                PathBuf::from("out/review_002.osm")
+            ]
+        );
+        assert_eq!(chunks[0].1.len(), 2);
+        assert_eq!(chunks[1].1.len(), 1);
+        // Node 3 is shared across the split, so both files get it
+        for (_, chunk) in &chunks {
+            let items = collect_items(&filtered, chunk, false);
+            assert!(items.iter().any(|obj| obj.id() == OsmId::Node(NodeId(3))));
+        }
+        assert_eq!(chunk_path(Path::new("review"), 12), Path::new("review_012"));
+    }
+
+    #[test]
+    fn split_by_changeset() {
+        let mut ways: Vec<_> = (10..13).map(|id| way(id, &[1])).collect();
+        for (obj, changeset) in ways.iter_mut().zip(&[Some(7), None, Some(7)]) {
+            if let OsmObj::Way(w) = obj {
+                w.info.changeset = *changeset;
+            }
+        }
+        let ways: Vec<_> = ways.iter().collect();
+        let chunks = split_output(
+            &ways,
+            Path::new("review.osm"),
+            None,
+            Some(SplitBy::Changeset),
+        );
+        let files: Vec<_> = chunks
+            .iter()
+            .map(|(path, chunk)| (path.to_string_lossy().into_owned(), chunk.len()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("review_unknown.osm".to_string(), 1),
+                ("review_7.osm".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn split_index_rectangles() {
+        let index = split_index(&[(Path::new("out_001.osm"), 2, [43.0, -71.5, 43.5, -71.0])]);
+        let feature = &index["features"][0];
+        assert_eq!(feature["properties"]["file"], "out_001.osm");
+        assert_eq!(feature["properties"]["ways"], 2);
+        let ring = feature["geometry"]["coordinates"][0].as_array().unwrap();
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring[0], ring[4]);
+        assert_eq!(ring[2], serde_json::json!([-71.0, 43.5]));
+    }
+
+    #[test]
+    fn merge_keeps_higher_version() {
+        let versioned = |id, version| {
+            let mut obj = way(id, &[1, 2]);
+            if let OsmObj::Way(w) = &mut obj {
+                w.info.version = Some(version);
+            }
+            obj
+        };
+        let mut merged = to_map(vec![versioned(10, 2), versioned(11, 3)]);
+        merge_objs(
+            &mut merged,
+            to_map(vec![versioned(10, 3), versioned(11, 1), versioned(12, 1)]),
+        );
+        let versions: Vec<_> = merged
+            .values()
+            .map(|obj| (obj.id().inner_id(), obj.version()))
+            .collect();
+        assert_eq!(versions, vec![(10, Some(3)), (11, Some(3)), (12, Some(1))]);
+    }
+
+    #[test]
+    fn directories_expand_to_pbfs() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in &["b.osm.pbf", "a.osm.pbf", "notes.txt"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let single = PathBuf::from("extra.osm.pbf");
+        let inputs = expand_inputs(&[dir.path().to_path_buf(), single.clone()]).unwrap();
+        assert_eq!(
+            inputs,
+            vec![
+                dir.path().join("a.osm.pbf"),
+                dir.path().join("b.osm.pbf"),
+                single
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_pbf_is_reported() {
+        let path = PathBuf::from("tests/fixtures/truncated.osm.pbf");
+        let err = read_inputs(&[path], false, |_| true).unwrap_err();
+        assert!(err.is::<CorruptInput>());
+        assert!(err.to_string().contains("corrupt or truncated"));
+        assert!(err.to_string().ends_with("(the first pass read 0 objects)"));
+    }
+
+    #[test]
+    fn malformed_ways() {
+        let check = |nodes: &[i64]| match way(1, nodes) {
+            OsmObj::Way(w) => malformed_way(&w),
+            _ => unreachable!(),
+        };
+        assert_eq!(check(&[1, 2, 3]), None);
+        assert_eq!(check(&[1, 2, 3, 1]), None);
+        assert_eq!(
+            check(&[1, 2, 2, 3]).as_deref(),
+            Some("node 2 is repeated back to back")
+        );
+        assert_eq!(
+            check(&[1, 2, 1, 3, 1]).as_deref(),
+            Some("node 1 appears 3 times")
+        );
+    }
+
+    #[test]
+    fn stage_predicates() {
+        let mut obj = way(10, &[1, 2]);
+        let w = match &mut obj {
+            OsmObj::Way(w) => w,
+            _ => unreachable!(),
+        };
+        assert_eq!(wrong_highway(w), Some("(none)"));
+        w.tags.insert("highway".into(), "residential".into());
+        assert_eq!(wrong_highway(w), Some("residential"));
+        w.tags.insert("highway".into(), "service".into());
+        assert_eq!(wrong_highway(w), None);
+
+        let filtered = to_map(vec![node(1)]);
+        assert_eq!(missing_nodes(&filtered, w), 1);
+
+        let opt = |args: &[&str]| {
+            Opt::from_iter_safe(std::iter::once("accessfix").chain(args.iter().copied())).unwrap()
+        };
+        assert!(length_allowed(None, &opt(&[])));
+        let min = opt(&["--min-length", "5"]);
+        assert!(length_allowed(Some(5.0), &min));
+        assert!(!length_allowed(Some(4.9), &min));
+        assert!(!length_allowed(None, &min));
+        assert!(length_allowed(
+            None,
+            &opt(&["--min-length", "5", "--keep-unmeasured"])
+        ));
+
+        let baseline: BTreeSet<_> = vec![OsmId::Way(WayId(10))].into_iter().collect();
+        assert!(!is_new(OsmId::Way(WayId(10)), &baseline));
+        assert!(is_new(OsmId::Way(WayId(11)), &baseline));
+    }
+
+    #[test]
+    fn bbox_keeps_partly_inside_ways() {
+        let bbox = parse_bbox("43,-72,44,-71").unwrap();
+        let inside = (43.5, -71.5);
+        let outside = (42.5, -71.5);
+        assert!(way_in_bbox(&bbox, &[inside, inside], true));
+        assert!(way_in_bbox(&bbox, &[outside, inside], false));
+        assert!(!way_in_bbox(&bbox, &[outside, inside], true));
+        assert!(!way_in_bbox(&bbox, &[outside, outside], false));
+        // A way with no known nodes is never inside
+        assert!(!way_in_bbox(&bbox, &[], false));
+        assert!(!way_in_bbox(&bbox, &[], true));
+        // The edges count as inside
+        assert!(way_in_bbox(&bbox, &[(43.0, -72.0)], true));
+    }
+
+    #[test]
+    fn haversine_distance() {
+        assert_eq!(haversine((43.0, -71.0), (43.0, -71.0)), 0.0);
+        // A thousandth of a degree of latitude is about 111 m
+        let d = haversine((43.0, -71.0), (43.001, -71.0));
+        assert!((d - 111.2).abs() < 0.1, "{}", d);
+    }
+
+    #[test]
+    fn overpass_chunks_long_lists() {
+        assert_eq!(overpass_query(&[1, 2]), "way(id:1,2);\nout geom;\n");
+        let ids: Vec<_> = (0..OVERPASS_CHUNK as i64 + 1).collect();
+        let query = overpass_query(&ids);
+        assert!(query.starts_with("(\n  way(id:0,1,"));
+        assert!(query.ends_with(",499);\n  way(id:500);\n);\nout geom;\n"));
+    }
+
+    #[test]
+    fn shared_node_written_once() {
+        let filtered = to_map(vec![
+            node(1),
+            node(2),
+            node(3),
+            way(10, &[1, 2]),
+            way(11, &[2, 3]),
+        ]);
+        let ways: Vec<_> = filtered.values().filter(|obj| obj.is_way()).collect();
+        let items = collect_items(&filtered, &ways, false);
+        assert_eq!(items.len(), 5);
+
+        let mut out = Vec::new();
+        write_osm(&mut out, &items, &options(None)).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert_eq!(xml.matches("<node id=\"2\"").count(), 1);
+        assert_eq!(xml.matches("<nd ref=\"2\"").count(), 2);
+    }
+
+    #[test]
+    fn output_is_sorted_and_reproducible() {
+        let filtered = to_map(vec![
+            node(1),
+            node(2),
+            node(3),
+            way(10, &[3, 2]),
+            way(11, &[2, 1]),
+            relation(20, &[10, 11]),
+        ]);
+        let write = |ways: &[&OsmObj]| {
+            let mut out = Vec::new();
+            write_osm(
+                &mut out,
+                &collect_items(&filtered, ways, false),
+                &options(None),
             )
             .unwrap();
-        writer.write(XmlEvent::end_element()).unwrap();
+            out
+        };
+        let mut ways: Vec<_> = filtered.values().filter(|obj| obj.is_way()).collect();
+        let first = write(&ways);
+        ways.reverse();
+        assert_eq!(first, write(&ways));
+
+        let ids: Vec<_> = collect_items(&filtered, &ways, false)
+            .iter()
+            .map(|obj| obj.id())
+            .collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(ids, sorted);
+    }
+
+    #[test]
+    fn context_adds_neighbouring_barriers() {
+        let mut gate = node(3);
+        if let OsmObj::Node(n) = &mut gate {
+            n.tags.insert("barrier".into(), "gate".into());
+        }
+        let filtered = to_map(vec![
+            node(1),
+            node(2),
+            gate,
+            node(4),
+            way(10, &[1, 2]),
+            way(11, &[2, 3]),
+            way(12, &[4]),
+        ]);
+        let kept = [&filtered[&OsmId::Way(WayId(10))]];
+        let ids = |context| -> Vec<_> {
+            collect_items(&filtered, &kept, context)
+                .iter()
+                .map(|obj| obj.id())
+                .collect()
+        };
+        assert!(!ids(false).contains(&OsmId::Node(NodeId(3))));
+        assert_eq!(
+            ids(true),
+            vec![
+                OsmId::Node(NodeId(1)),
+                OsmId::Node(NodeId(2)),
+                OsmId::Node(NodeId(3)),
+                OsmId::Way(WayId(10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn config_satisfies_requirements() {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        writeln!(file, "min_length = 5\nbbox = \"43,-72,44,-71\"").unwrap();
+        let args = vec![
+            "accessfix".into(),
+            "--keep-unmeasured".into(),
+            "--config".into(),
+            file.path().into(),
+            "--bbox-inside".into(),
+            "--min-length=7".into(),
+        ];
+        let opt = Opt::from_iter_safe(config::with_file_args(args).unwrap()).unwrap();
+        assert!(opt.keep_unmeasured && opt.bbox_inside);
+        assert_eq!(opt.min_length, Some(7.0));
+        assert_eq!(opt.bbox, Some([43.0, -72.0, 44.0, -71.0]));
+    }
+
+    #[test]
+    fn relation_with_a_kept_member() {
+        let filtered = to_map(vec![
+            node(1),
+            node(2),
+            node(3),
+            way(10, &[1, 2]),
+            way(11, &[2, 3]),
+            relation(20, &[10]),
+            relation(21, &[10, 11]),
+            relation(22, &[11]),
+        ]);
+        // Way 11 was dropped, so relation 21 is written with it incomplete
+        let ways = vec![&filtered[&OsmId::Way(WayId(10))]];
+        let items = collect_items(&filtered, &ways, false);
+        let ids: Vec<_> = items.iter().map(|obj| obj.id()).collect();
+        let written: HashSet<_> = ids.iter().copied().collect();
+        for (id, missing) in &[(20, 0), (21, 1)] {
+            match &filtered[&OsmId::Relation(RelationId(*id))] {
+                OsmObj::Relation(r) => assert_eq!(missing_members(r, &written), *missing),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(
+            ids,
+            vec![
+                OsmId::Node(NodeId(1)),
+                OsmId::Node(NodeId(2)),
+                OsmId::Way(WayId(10)),
+                OsmId::Relation(RelationId(20)),
+                OsmId::Relation(RelationId(21)),
+            ]
+        );
+    }
+
+    #[test]
+    fn relations_of_matched_ways_are_read() {
+        // Driveway 10 and street 11 are both in associatedStreet relation 20,
+        // and the street alone is in bus route 21
+        let inputs = vec![PathBuf::from("tests/fixtures/relation.osm.pbf")];
+        let driveway = |obj: &OsmObj| obj.tags().contains("service", "driveway");
+        let filtered = read_inputs(&inputs, true, driveway).unwrap();
+        assert!(filtered.contains_key(&OsmId::Relation(RelationId(20))));
+        assert!(!filtered.contains_key(&OsmId::Relation(RelationId(21))));
+        assert!(!filtered.contains_key(&OsmId::Way(WayId(11))));
+        let ways: Vec<_> = filtered.values().filter(|obj| obj.is_way()).collect();
+        let mut out = Vec::new();
+        write_osm(
+            &mut out,
+            &collect_items(&filtered, &ways, false),
+            &options(None),
+        )
+        .unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(xml.contains("<member type=\"way\" ref=\"11\" role=\"street\" />"));
+        assert!(xml.contains("<member type=\"way\" ref=\"10\" role=\"house\" />"));
+
+        let without = read_inputs(&inputs, false, driveway).unwrap();
+        assert!(!without.values().any(|obj| obj.is_relation()));
     }
 }